@@ -1,7 +1,10 @@
-use std::{fs::OpenOptions, io::BufReader};
+use std::{
+    fs::OpenOptions,
+    io::{BufReader, Cursor},
+};
 
 use criterion::{criterion_group, criterion_main, Criterion};
-use huffman::{hdecode, hencode};
+use huffman::{hdecode, hencode, hencode_interleaved};
 use tempfile::tempfile;
 
 fn criterion_benchmark(c: &mut Criterion) {
@@ -22,6 +25,7 @@ fn criterion_benchmark(c: &mut Criterion) {
     let mut out = OpenOptions::new()
         .write(true)
         .create(true)
+        .truncate(true)
         .open("flake.lock.rxc")
         .expect("file err");
     let mut raw = OpenOptions::new()
@@ -41,6 +45,27 @@ fn criterion_benchmark(c: &mut Criterion) {
             hdecode(&mut reader, &mut out).expect("io err");
         })
     });
+
+    // larger than flake.lock, so the decoding dominates the header
+    let large = std::fs::read("flake.lock").expect("file err").repeat(256);
+    let mut single = Vec::new();
+    hencode(&mut &large[..], &mut single).expect("io err");
+    let mut interleaved = Vec::new();
+    hencode_interleaved(&mut Cursor::new(&large), &mut interleaved).expect("io err");
+
+    integration.bench_function("decode_large", |bencher| {
+        bencher.iter(|| {
+            let mut out = Vec::with_capacity(large.len());
+            hdecode(&single[..], &mut out).expect("io err");
+        })
+    });
+
+    integration.bench_function("decode_interleaved_large", |bencher| {
+        bencher.iter(|| {
+            let mut out = Vec::with_capacity(large.len());
+            hdecode(&interleaved[..], &mut out).expect("io err");
+        })
+    });
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -0,0 +1,403 @@
+use std::io::{self, prelude::*};
+
+use crate::bitutils::Symbol;
+use crate::checksum::{read_window_trailer, write_trailer, Crc32};
+use crate::header::{self, Mode, HEADER_END};
+use crate::window::BitWindow;
+use crate::Error;
+
+/// Bits of the raw value following the escape code
+const ESCAPE_BITS: usize = 9;
+/// Raw value following the escape code that marks the end of the data,
+/// values below are bytes seen for the first time
+const END: usize = 256;
+/// Encoded bits up to this size are buffered before being written to the output
+const FLUSH_THRESHOLD: usize = 8 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Leaf(u8),
+    /// the leaf standing for all bytes that were not seen yet
+    Escape,
+    Internal {
+        left: usize,
+        right: usize,
+    },
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Node {
+    weight: usize,
+    parent: usize,
+    kind: Kind,
+}
+
+/// Huffman tree that is updated after every symbol using the FGK algorithm.
+///
+/// Nodes are stored in order of decreasing weight (the sibling property), the root is
+/// at index 0. Encoder and decoder start out with a tree containing only the escape leaf
+/// and apply the same updates, so the tree never has to be stored.
+struct AdaptiveTree {
+    nodes: Vec<Node>,
+    leaves: [Option<usize>; 256],
+    escape: usize,
+}
+
+impl AdaptiveTree {
+    fn new() -> Self {
+        AdaptiveTree {
+            nodes: vec![Node {
+                weight: 0,
+                parent: 0,
+                kind: Kind::Escape,
+            }],
+            leaves: [None; 256],
+            escape: 0,
+        }
+    }
+
+    /// Appends the code of `byte` to `output`, or the end marker if `byte` is [None]
+    fn encode(&self, byte: Option<u8>, output: &mut Symbol) {
+        match byte.and_then(|byte| self.leaves[byte as usize]) {
+            Some(leaf) => self.append_path(leaf, output),
+            None => {
+                self.append_path(self.escape, output);
+                let value = byte.map_or(END, usize::from);
+                for shift in (0..ESCAPE_BITS).rev() {
+                    output.append_bit((value >> shift) & 1 == 1);
+                }
+            }
+        }
+    }
+
+    /// Appends the bits leading from the root to `node`
+    fn append_path(&self, node: usize, output: &mut Symbol) {
+        if node == 0 {
+            return;
+        }
+        let parent = self.nodes[node].parent;
+        self.append_path(parent, output);
+        output.append_bit(
+            matches!(self.nodes[parent].kind, Kind::Internal { right, .. } if right == node),
+        );
+    }
+
+    /// Reads the next symbol from `window`
+    ///
+    /// # Returns
+    /// The decoded byte or [None] if the end marker was read
+    fn decode<R: BufRead>(&self, window: &mut BitWindow<R>) -> Result<Option<u8>, Error> {
+        let mut node = 0;
+        loop {
+            match self.nodes[node].kind {
+                Kind::Internal { left, right } => {
+                    let bit = window.show(1);
+                    window.consume(1)?;
+                    node = if bit == 1 { right } else { left };
+                }
+                Kind::Leaf(byte) => return Ok(Some(byte)),
+                Kind::Escape => {
+                    // the window only guarantees 8 visible bits
+                    let high = window.show(ESCAPE_BITS - 8);
+                    window.consume(ESCAPE_BITS - 8)?;
+                    let value = high << 8 | window.show(8);
+                    window.consume(8)?;
+                    return match value {
+                        END => Ok(None),
+                        value if value < END => Ok(Some(value as u8)),
+                        _ => Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "invalid value after escape code",
+                        )
+                        .into()),
+                    };
+                }
+            }
+        }
+    }
+
+    /// Increments the weight of `byte`, adding it to the tree if it was not seen before
+    fn update(&mut self, byte: u8) {
+        let mut node = match self.leaves[byte as usize] {
+            Some(leaf) => leaf,
+            None => {
+                // the escape leaf becomes the parent of the new leaf and a new escape leaf
+                let parent = self.escape;
+                let leaf = self.nodes.len();
+                self.escape = leaf + 1;
+                self.nodes[parent].kind = Kind::Internal {
+                    left: self.escape,
+                    right: leaf,
+                };
+                for kind in [Kind::Leaf(byte), Kind::Escape] {
+                    self.nodes.push(Node {
+                        weight: 0,
+                        parent,
+                        kind,
+                    });
+                }
+                self.leaves[byte as usize] = Some(leaf);
+                leaf
+            }
+        };
+
+        loop {
+            // move the node in front of all nodes with the same weight before incrementing
+            let weight = self.nodes[node].weight;
+            let mut leader = node;
+            while leader > 0 && self.nodes[leader - 1].weight == weight {
+                leader -= 1;
+            }
+            if leader != node && leader != self.nodes[node].parent {
+                self.swap(node, leader);
+                node = leader;
+            }
+            self.nodes[node].weight += 1;
+            if node == 0 {
+                break;
+            }
+            node = self.nodes[node].parent;
+        }
+    }
+
+    /// Swaps the subtrees at `a` and `b`, which must not be ancestors of each other
+    fn swap(&mut self, a: usize, b: usize) {
+        let (weight, kind) = (self.nodes[a].weight, self.nodes[a].kind);
+        self.nodes[a].weight = self.nodes[b].weight;
+        self.nodes[a].kind = self.nodes[b].kind;
+        self.nodes[b].weight = weight;
+        self.nodes[b].kind = kind;
+        for position in [a, b] {
+            match self.nodes[position].kind {
+                Kind::Leaf(byte) => self.leaves[byte as usize] = Some(position),
+                Kind::Escape => self.escape = position,
+                Kind::Internal { left, right } => {
+                    self.nodes[left].parent = position;
+                    self.nodes[right].parent = position;
+                }
+            }
+        }
+    }
+}
+
+/// Encodes `input` into `output` in a single pass using adaptive huffman coding.
+///
+/// See [`AdaptiveEncoder`] for a push based version.
+pub fn hencode_adaptive(input: &mut impl Read, output: &mut impl Write) -> Result<(), Error> {
+    let mut encoder = AdaptiveEncoder::new(output)?;
+    io::copy(input, &mut encoder)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+/// Push based adaptive huffman encoder wrapping a [`Write`].
+///
+/// Unlike [`Encoder`](crate::Encoder) no data has to be buffered, the tree starts out
+/// empty and is updated after every byte in the same way the decoder updates its tree.
+/// Encoded bytes are passed on to the wrapped writer as soon as they are complete,
+/// which makes this mode suitable for live streams.
+///
+/// [`finish`] has to be called to mark the end of the data, dropping the encoder
+/// without calling it leaves the output truncated.
+///
+/// [`finish`]: AdaptiveEncoder::finish
+pub struct AdaptiveEncoder<W: Write> {
+    output: W,
+    tree: AdaptiveTree,
+    encoded: Symbol,
+    crc: Crc32,
+}
+
+impl<W: Write> AdaptiveEncoder<W> {
+    /// Writes the header to `output` and prepares encoding
+    pub fn new(mut output: W) -> Result<Self, Error> {
+        header::write_mode(&mut output, Mode::Adaptive)?;
+        output.write_all(HEADER_END)?;
+        Ok(AdaptiveEncoder {
+            output,
+            tree: AdaptiveTree::new(),
+            encoded: Symbol {
+                bytes: Vec::with_capacity(FLUSH_THRESHOLD + 1),
+                bitpos: 0,
+                bytepos: 0,
+            },
+            crc: Crc32::new(),
+        })
+    }
+
+    /// Writes the end marker, the remaining bits padded with zeros and the checksum
+    ///
+    /// # Returns
+    /// The wrapped writer
+    pub fn finish(mut self) -> Result<W, Error> {
+        self.tree.encode(None, &mut self.encoded);
+        self.output.write_all(&self.encoded.bytes)?;
+        write_trailer(&mut self.output, &self.crc)?;
+        self.output.flush()?;
+        Ok(self.output)
+    }
+}
+
+impl<W: Write> Write for AdaptiveEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.tree.encode(Some(byte), &mut self.encoded);
+            self.tree.update(byte);
+            if self.encoded.bytepos >= FLUSH_THRESHOLD {
+                self.encoded.write_complete(&mut self.output)?;
+            }
+        }
+        self.crc.update(buf);
+        Ok(buf.len())
+    }
+
+    /// Writes all complete bytes to the wrapped writer and flushes it.
+    ///
+    /// Up to 7 bits stay buffered until more data is written or [`AdaptiveEncoder::finish`]
+    /// is called.
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoded.write_complete(&mut self.output)?;
+        self.output.flush()
+    }
+}
+
+/// Pull based decoder for data encoded by [`AdaptiveEncoder`].
+pub struct AdaptiveDecoder<R: BufRead> {
+    tree: AdaptiveTree,
+    window: BitWindow<R>,
+    done: bool,
+    crc: Crc32,
+}
+
+impl<R: BufRead> AdaptiveDecoder<R> {
+    /// Reads the header from `input` and prepares decoding
+    ///
+    /// # Errors
+    /// This method returns an error if `input` does not start with a valid adaptive mode header
+    pub fn new(mut input: R) -> Result<Self, Error> {
+        if header::read_mode(&mut input)? != Mode::Adaptive {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file is not adaptively encoded, use hdecode instead",
+            )
+            .into());
+        }
+        AdaptiveDecoder::after_mode(input)
+    }
+
+    /// Prepares decoding adaptive mode data from `input` that is positioned after the mode
+    pub(crate) fn after_mode(mut input: R) -> Result<Self, Error> {
+        header::read_end(&mut input)?;
+        Ok(AdaptiveDecoder {
+            tree: AdaptiveTree::new(),
+            window: input.into(),
+            done: false,
+            crc: Crc32::new(),
+        })
+    }
+
+    /// Reads the trailer following the end marker and compares it to the decoded data
+    fn verify(&mut self) -> Result<(), Error> {
+        self.crc.verify(read_window_trailer(&mut self.window)?)
+    }
+}
+
+impl<R: BufRead> Read for AdaptiveDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut written = 0;
+        while written < buf.len() && !self.done {
+            match self.tree.decode(&mut self.window)? {
+                Some(byte) => {
+                    self.tree.update(byte);
+                    buf[written] = byte;
+                    written += 1;
+                }
+                None => {
+                    self.done = true;
+                    self.crc.update(&buf[..written]);
+                    self.verify()?;
+                    return Ok(written);
+                }
+            }
+        }
+        self.crc.update(&buf[..written]);
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hdecode;
+
+    fn roundtrip(raw: &[u8]) -> Vec<u8> {
+        let mut coded = Vec::new();
+        hencode_adaptive(&mut &raw[..], &mut coded).expect("encoding failed");
+        let mut out = Vec::new();
+        hdecode(&coded[..], &mut out).expect("decoding failed");
+        assert_eq!(raw, out);
+        coded
+    }
+
+    #[test]
+    fn edge_cases() {
+        roundtrip(&[]);
+        roundtrip(&[0]);
+        roundtrip(&[255; 1000]);
+        roundtrip(&(0..=u8::MAX).cycle().take(5000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn compresses_text() {
+        let raw = b"adaptive huffman coding learns the distribution while it goes. ".repeat(50);
+        let coded = roundtrip(&raw);
+        assert!(coded.len() < raw.len() * 6 / 10);
+    }
+
+    #[test]
+    fn sibling_property() {
+        let mut tree = AdaptiveTree::new();
+        for &byte in b"abracadabra, mississippi and the fibonacci sequence".iter() {
+            tree.update(byte);
+            for (index, node) in tree.nodes.iter().enumerate() {
+                if index > 0 {
+                    assert!(tree.nodes[index - 1].weight >= node.weight);
+                }
+                if let Kind::Internal { left, right } = node.kind {
+                    assert_eq!(tree.nodes[left].parent, index);
+                    assert_eq!(tree.nodes[right].parent, index);
+                    assert_eq!(
+                        tree.nodes[left].weight + tree.nodes[right].weight,
+                        node.weight
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn streaming() {
+        let raw = b"live data arrives in pieces ".repeat(1000);
+        let mut encoder = AdaptiveEncoder::new(Vec::new()).expect("io err");
+        encoder.write_all(&raw).expect("io err");
+        encoder.flush().expect("io err");
+        let written = encoder.output.len();
+        assert!(written > HEADER_END.len() + raw.len() / 4);
+        let coded = encoder.finish().expect("encoding failed");
+
+        // the data flushed so far can be decoded before the encoder has finished
+        let mut decoder = AdaptiveDecoder::new(&coded[..written]).expect("invalid header");
+        let mut out = vec![0u8; raw.len() / 2];
+        decoder.read_exact(&mut out).expect("decoding failed");
+        assert_eq!(&raw[..out.len()], &out);
+    }
+
+    #[test]
+    fn truncated() {
+        let mut coded = Vec::new();
+        hencode_adaptive(&mut &b"cut off before the end marker"[..], &mut coded)
+            .expect("encoding failed");
+        coded.pop();
+        assert!(hdecode(&coded[..], Vec::new()).is_err());
+    }
+}
@@ -1,4 +1,5 @@
 use std::fmt;
+use std::io::{self, Read, Write};
 
 #[derive(Debug, Clone)]
 pub struct Symbol {
@@ -72,6 +73,111 @@ impl Symbol {
             self.append_byte(sym.bytes[byteindex], sym.bitpos);
         }
     }
+
+    /// Appends the lowest `bits` bits of `value`, most significant bit first
+    pub fn append_bits(&mut self, value: u32, bits: u8) {
+        for shift in (0..bits).rev() {
+            self.append_bit((value >> shift) & 1 == 1);
+        }
+    }
+
+    /// Appends the lowest `bits` bits of `value`, least significant bit first.
+    ///
+    /// Used for numbers in streams that are written using [`write_complete_lsb_first`].
+    ///
+    /// [`write_complete_lsb_first`]: Symbol::write_complete_lsb_first
+    pub fn append_bits_lsb_first(&mut self, value: u32, bits: u8) {
+        for shift in 0..bits {
+            self.append_bit((value >> shift) & 1 == 1);
+        }
+    }
+
+    /// Pads the last byte with 0s, so the next bit starts a new byte
+    pub fn pad_to_byte(&mut self) {
+        if self.bitpos > 0 {
+            self.bitpos = 8;
+        }
+    }
+
+    /// returns the bit at `index`, counting from the most significant bit of the first byte
+    pub fn bit(&self, index: usize) -> bool {
+        self.bytes[index / 8] & (128 >> (index % 8)) != 0
+    }
+
+    /// number of bits stored in `self`
+    pub fn bits(&self) -> usize {
+        if self.bytes.is_empty() {
+            0
+        } else {
+            self.bytepos * 8 + self.bitpos
+        }
+    }
+
+    /// Writes all completely filled bytes to `output` and removes them from `self`.
+    ///
+    /// The partially filled last byte (if any) is kept so appending can continue
+    /// where it left off.
+    pub fn write_complete(&mut self, output: &mut impl Write) -> io::Result<()> {
+        if self.bitpos == 8 {
+            output.write_all(&self.bytes)?;
+            self.bytes.clear();
+            self.bitpos = 0;
+        } else {
+            output.write_all(&self.bytes[..self.bytepos])?;
+            self.bytes.drain(..self.bytepos);
+        }
+        self.bytepos = 0;
+        Ok(())
+    }
+
+    /// Like [`write_complete`], but reverses the bits of every written byte.
+    ///
+    /// This packs the bits starting with the least significant bit of each byte as in
+    /// RFC 1951, while huffman codes can be appended as usual. It is the counterpart of
+    /// [`BitWindow::lsb_first`](crate::window::BitWindow::lsb_first).
+    ///
+    /// [`write_complete`]: Symbol::write_complete
+    pub fn write_complete_lsb_first(&mut self, output: &mut impl Write) -> io::Result<()> {
+        let complete = if self.bitpos == 8 {
+            self.bytes.len()
+        } else {
+            self.bytepos
+        };
+        for byte in self.bytes[..complete].iter_mut() {
+            *byte = byte.reverse_bits();
+        }
+        self.write_complete(output)
+    }
+}
+
+/// Writes `value` as LEB128 varint: 7 bits per byte, least significant group first,
+/// the most significant bit of each byte is set if more bytes follow.
+pub fn write_varint(output: &mut impl Write, mut value: u64) -> io::Result<()> {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            return output.write_all(&[byte]);
+        }
+        output.write_all(&[byte | 0x80])?;
+    }
+}
+
+/// Reads a varint written by [`write_varint`]
+pub fn read_varint(input: &mut impl Read) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8];
+        input.read_exact(&mut byte)?;
+        value |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "varint too long",
+    ))
 }
 
 #[cfg(test)]
@@ -256,4 +362,61 @@ mod tests {
         assert_eq!(1, empty.bitpos);
         assert_eq!(1, empty.bytepos);
     }
+
+    #[test]
+    fn write_complete_keeps_partial() {
+        let mut sym = Symbol {
+            bytes: vec![0b11000000, 0b10100000],
+            bitpos: 3,
+            bytepos: 1,
+        };
+        let mut out = Vec::new();
+        sym.write_complete(&mut out).expect("io err");
+        assert_eq!(vec![0b11000000], out);
+        assert_eq!(vec![0b10100000], sym.bytes);
+        assert_eq!(3, sym.bitpos);
+        assert_eq!(0, sym.bytepos);
+
+        sym.append_sym(&Symbol {
+            bytes: vec![0b11111000],
+            bitpos: 5,
+            bytepos: 0,
+        });
+        sym.write_complete(&mut out).expect("io err");
+        assert_eq!(vec![0b11000000, 0b10111111], out);
+        assert!(sym.bytes.is_empty());
+        assert_eq!(0, sym.bits());
+    }
+
+    #[test]
+    fn lsb_first() {
+        let mut sym = Symbol {
+            bytes: Vec::new(),
+            bitpos: 0,
+            bytepos: 0,
+        };
+        sym.append_bits_lsb_first(0b110, 3);
+        sym.append_bits(0b10, 2);
+        sym.pad_to_byte();
+        sym.append_bits_lsb_first(0xabc, 12);
+        let mut out = Vec::new();
+        sym.write_complete_lsb_first(&mut out).expect("io err");
+        assert_eq!(vec![0b0000_1110, 0xbc], out);
+        assert_eq!(4, sym.bits());
+        sym.pad_to_byte();
+        sym.write_complete_lsb_first(&mut out).expect("io err");
+        assert_eq!(vec![0b0000_1110, 0xbc, 0x0a], out);
+    }
+
+    #[test]
+    fn varint_roundtrip() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, value).expect("io err");
+            assert_eq!(value, read_varint(&mut &buf[..]).expect("io err"));
+        }
+        let mut buf = Vec::new();
+        write_varint(&mut buf, 300).expect("io err");
+        assert_eq!(vec![0b1010_1100, 0b0000_0010], buf);
+    }
 }
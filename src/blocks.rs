@@ -0,0 +1,348 @@
+use std::io::{self, prelude::*, Take};
+use std::rc::Rc;
+
+use crate::bitutils::{read_varint, write_varint};
+use crate::checksum::{read_trailer, write_trailer, Crc32};
+use crate::header::{self, Mode, HEADER_END};
+use crate::table::Table;
+use crate::tree::Tree;
+use crate::{encode_payload, leaves, payload_bits, Decoder, Error};
+
+/// Block size that works well for general purpose data
+pub const DEFAULT_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// marks the end of the block sequence
+const END: u8 = 0;
+/// the block starts with the code lengths of its own tree
+const NEW_TREE: u8 = 1;
+/// the block uses the tree of the last block that stored one
+const REUSE_TREE: u8 = 2;
+/// flag of the kind, the payload is followed by the checksum of the block
+const CHECKSUM: u8 = 0x80;
+
+/// Encodes `input` into `output` in blocks of `block_size` bytes.
+///
+/// Every block gets its own tree, unless encoding it with the tree of the previous block
+/// is smaller than storing a new one. Only one block is kept in memory at a time, so
+/// memory usage is bounded by `block_size` no matter how large `input` is.
+///
+/// Each block is stored as its kind, the code lengths if it has its own tree, the number
+/// of bytes and the length of the payload as varints and the payload itself. The sequence
+/// of blocks is terminated by an end marker.
+///
+/// # Panics
+/// This function panics if `block_size` is 0
+pub fn hencode_blocks(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    block_size: usize,
+) -> Result<(), Error> {
+    encode_blocks(input, output, block_size, false)
+}
+
+/// Encodes `input` into `output` like [`hencode_blocks`], storing the checksum of every
+/// block after its payload.
+///
+/// Costs 4 bytes per block, but a corrupted block is detected as soon as it is decoded
+/// instead of at the end of the data.
+///
+/// # Panics
+/// This function panics if `block_size` is 0
+pub fn hencode_blocks_with_checksums(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    block_size: usize,
+) -> Result<(), Error> {
+    encode_blocks(input, output, block_size, true)
+}
+
+fn encode_blocks(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    block_size: usize,
+    block_checksums: bool,
+) -> Result<(), Error> {
+    assert!(block_size > 0, "blocks must not be empty");
+    let flag = if block_checksums { CHECKSUM } else { 0 };
+    let mut crc = Crc32::new();
+    header::write_mode(output, Mode::Blocks)?;
+    output.write_all(HEADER_END)?;
+
+    let mut block = Vec::with_capacity(block_size);
+    let mut payload = Vec::new();
+    let mut previous: Option<Tree> = None;
+    loop {
+        block.clear();
+        input
+            .by_ref()
+            .take(block_size as u64)
+            .read_to_end(&mut block)?;
+        if block.is_empty() {
+            break;
+        }
+        let mut counts = [0usize; 256];
+        for &byte in block.iter() {
+            counts[byte as usize] += 1;
+        }
+        crc.update(&block);
+
+        let tree = Tree::mktree(leaves(&counts));
+        let mut lengths = Vec::new();
+        tree.store_lengths(&mut lengths)?;
+        let new_cost = lengths.len() * 8
+            + payload_bits(&tree.code_lengths(), &counts).expect("tree contains all bytes");
+        let reuse_cost = previous
+            .as_ref()
+            .and_then(|previous| payload_bits(&previous.code_lengths(), &counts));
+
+        match reuse_cost {
+            Some(reuse_cost) if reuse_cost <= new_cost => {
+                output.write_all(&[REUSE_TREE | flag])?;
+            }
+            _ => {
+                output.write_all(&[NEW_TREE | flag])?;
+                output.write_all(&lengths)?;
+                previous = Some(tree);
+            }
+        }
+
+        payload.clear();
+        let tree = previous.as_ref().expect("set above");
+        encode_payload(tree, &mut &block[..], &mut payload)?;
+        write_varint(output, block.len() as u64)?;
+        write_varint(output, payload.len() as u64)?;
+        output.write_all(&payload)?;
+        if block_checksums {
+            let mut block_crc = Crc32::new();
+            block_crc.update(&block);
+            write_trailer(output, &block_crc)?;
+        }
+    }
+    output.write_all(&[END])?;
+    write_trailer(output, &crc)?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Pull based decoder for data encoded by [`hencode_blocks`].
+///
+/// Switches to the table of each block as the blocks are reached, only the table
+/// of the current block is kept in memory.
+pub struct BlockDecoder<R: BufRead> {
+    /// table of the last block that stored a tree
+    table: Option<Rc<Table>>,
+    /// the reader, while no block is being decoded
+    input: Option<R>,
+    decoder: Option<Decoder<Take<R>, Rc<Table>>>,
+    done: bool,
+    crc: Crc32,
+    /// checksum of the current block, if it stores one
+    block_crc: Option<Crc32>,
+}
+
+impl<R: BufRead> BlockDecoder<R> {
+    /// Reads the header from `input` and prepares decoding the blocks
+    ///
+    /// # Errors
+    /// This method returns an error if `input` does not start with a valid block mode header
+    pub fn new(mut input: R) -> Result<Self, Error> {
+        if header::read_mode(&mut input)? != Mode::Blocks {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file is not encoded in blocks, use hdecode or Decoder",
+            )
+            .into());
+        }
+        BlockDecoder::after_mode(input)
+    }
+
+    /// Prepares decoding block mode data from `input` that is positioned after the mode
+    pub(crate) fn after_mode(mut input: R) -> Result<Self, Error> {
+        header::read_end(&mut input)?;
+        Ok(BlockDecoder {
+            table: None,
+            input: Some(input),
+            decoder: None,
+            done: false,
+            crc: Crc32::new(),
+            block_crc: None,
+        })
+    }
+
+    /// Reads the header of the next block and starts decoding it
+    fn next_block(&mut self) -> Result<(), Error> {
+        let mut input = self.input.take().expect("no block is being decoded");
+        let mut kind = [0u8];
+        input.read_exact(&mut kind)?;
+        match kind[0] {
+            END => {
+                self.done = true;
+                self.crc.verify(read_trailer(&mut input)?)?;
+                self.input = Some(input);
+                return Ok(());
+            }
+            kind => self.block_crc = (kind & CHECKSUM != 0).then(Crc32::new),
+        }
+        match kind[0] & !CHECKSUM {
+            NEW_TREE => {
+                let lengths = Tree::<u8>::load_lengths(&mut input)?;
+                let table = Table::from_lengths(&lengths).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "code cannot be decoded")
+                })?;
+                self.table = Some(Rc::new(table));
+            }
+            REUSE_TREE => (),
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown block kind").into())
+            }
+        }
+        let table = self.table.clone().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "first block does not store a tree",
+            )
+        })?;
+        let bytes = read_varint(&mut input)?;
+        let len = read_varint(&mut input)?;
+        self.decoder = Some(Decoder::with_size(table, input.take(len), bytes, len)?);
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for BlockDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if let Some(decoder) = self.decoder.as_mut() {
+                let read = decoder.read(buf)?;
+                if read > 0 {
+                    self.crc.update(&buf[..read]);
+                    if let Some(block_crc) = self.block_crc.as_mut() {
+                        block_crc.update(&buf[..read]);
+                    }
+                    return Ok(read);
+                }
+                // the decoder checked that the keys took the whole payload
+                let decoder = self.decoder.take().expect("checked above");
+                let mut input = decoder.into_inner().into_inner();
+                if let Some(block_crc) = self.block_crc.take() {
+                    block_crc.verify(read_trailer(&mut input)?)?;
+                }
+                self.input = Some(input);
+            }
+            if self.done {
+                return Ok(0);
+            }
+            self.next_block()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hdecode, hencode};
+
+    fn roundtrip(raw: &[u8], block_size: usize) -> Vec<u8> {
+        let mut coded = Vec::new();
+        hencode_blocks(&mut &raw[..], &mut coded, block_size).expect("encoding failed");
+        let mut out = Vec::new();
+        hdecode(&coded[..], &mut out).expect("decoding failed");
+        assert_eq!(raw, out);
+
+        let mut out = Vec::new();
+        BlockDecoder::new(&coded[..])
+            .expect("invalid header")
+            .read_to_end(&mut out)
+            .expect("decoding failed");
+        assert_eq!(raw, out);
+        coded
+    }
+
+    #[test]
+    fn non_stationary() {
+        // text followed by binary data compresses better with a tree per block
+        let mut raw = b"the quick brown fox jumps over the lazy dog. ".repeat(200);
+        raw.extend((0..=u8::MAX).cycle().take(4000).map(|byte| byte / 16 + 200));
+        let coded = roundtrip(&raw, 1000);
+
+        let mut single = Vec::new();
+        hencode(&mut &raw[..], &mut single).expect("encoding failed");
+        assert!(coded.len() < single.len());
+    }
+
+    #[test]
+    fn reuse_tree() {
+        let raw = b"abababababababababababababababababababcd".repeat(50);
+        let coded = roundtrip(&raw, 100);
+        let reused = coded.iter().filter(|&&byte| byte == REUSE_TREE).count();
+        assert!(reused > 0);
+    }
+
+    #[test]
+    fn edge_cases() {
+        roundtrip(&[], 10);
+        roundtrip(&[7], 10);
+        roundtrip(&[7; 10], 10);
+        roundtrip(&[7; 11], 10);
+        roundtrip(b"single byte blocks", 1);
+    }
+
+    #[test]
+    fn block_checksums() {
+        let raw = b"every block is followed by its own checksum. ".repeat(20);
+        let plain = roundtrip(&raw, 300);
+        let mut coded = Vec::new();
+        hencode_blocks_with_checksums(&mut &raw[..], &mut coded, 300).expect("encoding failed");
+        assert_eq!(plain.len() + 4 * raw.len().div_ceil(300), coded.len());
+
+        // the checksum of the last block, in front of the end marker and the trailer
+        let last = coded.len() - 6;
+        coded[last] ^= 1;
+        let mut decoder = BlockDecoder::new(&coded[..]).expect("invalid header");
+        let mut out = vec![0; 600];
+        decoder
+            .read_exact(&mut out)
+            .expect("first blocks are intact");
+        assert_eq!(&raw[..600], &out);
+        let err = decoder
+            .read_to_end(&mut out)
+            .expect_err("last block is corrupted");
+        assert!(matches!(Error::from(err), Error::ChecksumMismatch { .. }));
+    }
+
+    #[test]
+    fn padded_payload() {
+        let raw = b"a single block, padded with a few bytes. ".repeat(10);
+        let coded = roundtrip(&raw, raw.len());
+        let mut input = &coded[..];
+        header::read_mode(&mut input).expect("invalid header");
+        header::read_end(&mut input).expect("invalid header");
+        input = &input[1..];
+        Tree::<u8>::load_lengths(&mut input).expect("invalid block");
+        read_varint(&mut input).expect("invalid block");
+        let start = coded.len() - input.len();
+        let len = read_varint(&mut input).expect("invalid block");
+        let payload = coded.len() - input.len();
+        let end = payload + len as usize;
+
+        for extra in 1..8 {
+            let mut padded = coded[..start].to_vec();
+            write_varint(&mut padded, len + extra).expect("io err");
+            padded.extend(&coded[payload..end]);
+            padded.extend(vec![0; extra as usize]);
+            padded.extend(&coded[end..]);
+            let err = hdecode(&padded[..], Vec::new()).expect_err("payload is padded");
+            assert!(matches!(err, Error::Overlong { .. }), "{extra}: {err}");
+        }
+    }
+
+    #[test]
+    fn wrong_mode() {
+        let mut coded = Vec::new();
+        hencode_blocks(&mut &b"blocks"[..], &mut coded, 10).expect("encoding failed");
+        assert!(Decoder::new(&coded[..]).is_err());
+    }
+}
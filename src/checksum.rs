@@ -0,0 +1,149 @@
+use std::io::{self, prelude::*};
+
+use crate::tree::Key;
+use crate::window::BitWindow;
+use crate::Error;
+
+/// Number of bytes of the CRC32 of the original data following every encoded file
+const TRAILER_LEN: usize = 4;
+
+/// Lookup table for the reflected CRC32 polynomial used by gzip and zip
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut crc = byte as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                crc >> 1 ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[byte] = crc;
+        byte += 1;
+    }
+    table
+};
+
+/// Largest prime smaller than 2^16
+const ADLER_MOD: u32 = 65521;
+/// Number of bytes that can be summed before the sums of [`Adler32`] could overflow
+const ADLER_CHUNK: usize = 5552;
+
+/// Running CRC32 (ISO 3309) as stored in gzip trailers
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Crc32 {
+    crc: u32,
+}
+
+impl Crc32 {
+    pub(crate) const fn new() -> Self {
+        Crc32 { crc: 0xffff_ffff }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.crc = CRC32_TABLE[(self.crc as u8 ^ byte) as usize] ^ self.crc >> 8;
+        }
+    }
+
+    /// Updates the checksum with the little endian bytes of `key`
+    pub(crate) fn update_key<K: Key>(&mut self, key: K) {
+        let bytes = key.index().to_le_bytes();
+        self.update(&bytes[..K::BITS as usize / 8]);
+    }
+
+    pub(crate) const fn sum(&self) -> u32 {
+        !self.crc
+    }
+
+    /// # Errors
+    /// This method returns [`Error::ChecksumMismatch`] if the checksum is not `expected`
+    pub(crate) fn verify(&self, expected: u32) -> Result<(), Error> {
+        if self.sum() != expected {
+            return Err(Error::ChecksumMismatch {
+                expected,
+                found: self.sum(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Writes the checksum of `crc` as trailer
+pub(crate) fn write_trailer(output: &mut impl Write, crc: &Crc32) -> io::Result<()> {
+    output.write_all(&crc.sum().to_le_bytes())
+}
+
+/// Reads a trailer written by [`write_trailer`]
+pub(crate) fn read_trailer(input: &mut impl Read) -> io::Result<u32> {
+    let mut trailer = [0u8; TRAILER_LEN];
+    input.read_exact(&mut trailer)?;
+    Ok(u32::from_le_bytes(trailer))
+}
+
+/// Reads a trailer written by [`write_trailer`] after a payload read through `window`
+pub(crate) fn read_window_trailer<R: BufRead>(window: &mut BitWindow<R>) -> Result<u32, Error> {
+    // the trailer starts at the next byte, which may be loaded into the window already
+    window.consume(window.initialized() % 8)?;
+    let mut trailer = [0u8; TRAILER_LEN];
+    for byte in trailer.iter_mut() {
+        *byte = window.show(8) as u8;
+        window.consume(8)?;
+    }
+    Ok(u32::from_le_bytes(trailer))
+}
+
+/// Running Adler-32 as stored in zlib trailers
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Adler32 {
+    a: u32,
+    b: u32,
+}
+
+impl Adler32 {
+    pub(crate) const fn new() -> Self {
+        Adler32 { a: 1, b: 0 }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        for chunk in data.chunks(ADLER_CHUNK) {
+            for &byte in chunk {
+                self.a += byte as u32;
+                self.b += self.a;
+            }
+            self.a %= ADLER_MOD;
+            self.b %= ADLER_MOD;
+        }
+    }
+
+    pub(crate) const fn sum(&self) -> u32 {
+        self.b << 16 | self.a
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_values() {
+        let mut crc = Crc32::new();
+        assert_eq!(0, crc.sum());
+        crc.update(b"123456789");
+        assert_eq!(0xcbf4_3926, crc.sum());
+
+        let mut adler = Adler32::new();
+        assert_eq!(1, adler.sum());
+        adler.update(b"Wikipedia");
+        assert_eq!(0x11e6_0398, adler.sum());
+
+        // large enough to require the modulo in between
+        let mut adler = Adler32::new();
+        adler.update(&[0xff; 100_000]);
+        assert_eq!(0x149a_302c, adler.sum());
+    }
+}
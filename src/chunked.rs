@@ -0,0 +1,431 @@
+use std::io::{self, prelude::*};
+use std::num::NonZeroUsize;
+use std::thread::{self, ScopedJoinHandle};
+
+use crate::bitutils::{read_varint, write_varint};
+use crate::checksum::{read_trailer, write_trailer, Crc32};
+use crate::header::{self, Mode, HEADER_END};
+use crate::table::Table;
+use crate::tree::Tree;
+use crate::{count, encode_payload, leaves, payload_bits, Decoder, Error};
+
+/// Chunk size that keeps all threads busy without using much memory per thread
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Largest chunk size accepted by [`hencode_chunked`] and [`ChunkedDecoder`], the
+/// decoder keeps one chunk per thread in memory
+pub const MAX_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+
+/// marks the end of the chunk sequence
+const END: u8 = 0;
+/// the chunk uses the shared tree stored in the header
+const SHARED_TREE: u8 = 1;
+/// the chunk starts with the code lengths of its own tree
+const OWN_TREE: u8 = 2;
+
+/// Number of threads [`hdecode`](crate::hdecode) uses for chunked data
+pub(crate) fn default_threads() -> usize {
+    thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
+
+/// Encodes `input` starting at its current position into `output` in chunks of
+/// `chunk_size` bytes, using `threads` threads.
+///
+/// The header stores a tree over all data that is shared by the chunks, chunks that
+/// are smaller with a tree of their own store that instead. Every chunk only depends on
+/// the header, so the chunks are encoded and decoded in parallel.
+///
+/// Each chunk is stored as its kind, the code lengths if it has its own tree, the number
+/// of bytes and the length of the payload as varints and the payload itself. The output
+/// only depends on the data and `chunk_size`, not on the number of threads.
+///
+/// Up to `threads` chunks are kept in memory at a time.
+///
+/// # Panics
+/// This function panics if `chunk_size` or `threads` is 0, or if `chunk_size` is larger
+/// than [`MAX_CHUNK_SIZE`]
+pub fn hencode_chunked(
+    input: &mut (impl Read + Seek),
+    output: &mut impl Write,
+    chunk_size: usize,
+    threads: usize,
+) -> Result<(), Error> {
+    assert!(chunk_size > 0, "chunks must not be empty");
+    assert!(
+        chunk_size <= MAX_CHUNK_SIZE,
+        "chunks must not be larger than {MAX_CHUNK_SIZE} bytes"
+    );
+    assert!(threads > 0, "at least one thread is needed");
+    let (counts, crc) = count(input)?;
+    let shared = Tree::mktree(leaves(&counts));
+    header::write_mode(output, Mode::Chunked)?;
+    write_varint(output, chunk_size as u64)?;
+    shared.store_lengths(output)?;
+    output.write_all(HEADER_END)?;
+
+    let shared_lengths = shared.code_lengths();
+    let mut batch = Vec::with_capacity(threads);
+    loop {
+        batch.clear();
+        while batch.len() < threads {
+            let mut chunk = Vec::with_capacity(chunk_size);
+            (&mut *input)
+                .take(chunk_size as u64)
+                .read_to_end(&mut chunk)?;
+            if chunk.is_empty() {
+                break;
+            }
+            batch.push(chunk);
+        }
+        if batch.is_empty() {
+            break;
+        }
+        let encoded = thread::scope(|scope| {
+            let workers: Vec<_> = batch
+                .iter()
+                .map(|chunk| scope.spawn(|| encode_chunk(chunk, &shared, &shared_lengths)))
+                .collect();
+            workers.into_iter().map(join).collect::<Result<Vec<_>, _>>()
+        })?;
+        for chunk in encoded {
+            output.write_all(&chunk)?;
+        }
+    }
+    output.write_all(&[END])?;
+    write_trailer(output, &crc)?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Encodes `chunk` with the shared tree or a tree of its own, whichever is smaller
+fn encode_chunk(chunk: &[u8], shared: &Tree, shared_lengths: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut counts = [0usize; 256];
+    for &byte in chunk {
+        counts[byte as usize] += 1;
+    }
+    let own = Tree::mktree(leaves(&counts));
+    let mut lengths = Vec::new();
+    own.store_lengths(&mut lengths)?;
+    let own_cost = lengths.len() * 8
+        + payload_bits(&own.code_lengths(), &counts).expect("tree contains all bytes");
+    let shared_cost =
+        payload_bits(shared_lengths, &counts).expect("shared tree contains all bytes");
+
+    let mut encoded = Vec::new();
+    let tree = if own_cost < shared_cost {
+        encoded.push(OWN_TREE);
+        encoded.extend(lengths);
+        &own
+    } else {
+        encoded.push(SHARED_TREE);
+        shared
+    };
+    let mut payload = Vec::new();
+    encode_payload(tree, &mut &chunk[..], &mut payload)?;
+    write_varint(&mut encoded, chunk.len() as u64)?;
+    write_varint(&mut encoded, payload.len() as u64)?;
+    encoded.extend(payload);
+    Ok(encoded)
+}
+
+/// Waits for `worker`, resuming its panic if it panicked
+fn join<T>(worker: ScopedJoinHandle<'_, T>) -> T {
+    worker
+        .join()
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
+
+/// Chunk read by [`ChunkedDecoder`] that has not been decoded yet
+struct Chunk {
+    /// code lengths of its own tree, if it does not use the shared one
+    lengths: Option<Vec<u8>>,
+    len: usize,
+    payload: Vec<u8>,
+}
+
+impl Chunk {
+    /// Decodes the chunk into `output`, which has to be `len` bytes long
+    fn decode(&self, shared: &Table, output: &mut [u8]) -> Result<(), Error> {
+        let own;
+        let table = match self.lengths.as_ref() {
+            Some(lengths) => {
+                own = Table::from_lengths(lengths).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "code cannot be decoded")
+                })?;
+                &own
+            }
+            None => shared,
+        };
+        let size = self.payload.len() as u64;
+        // the decoder checks that the keys take the whole payload
+        let mut decoder = Decoder::with_size(table, &self.payload[..], self.len as u64, size)?;
+        decoder.read_exact(output)?;
+        Ok(())
+    }
+}
+
+/// Pull based decoder for data encoded by [`hencode_chunked`].
+///
+/// Reads batches of one chunk per thread and decodes them in parallel, only the chunks
+/// of the current batch are kept in memory.
+pub struct ChunkedDecoder<R: BufRead> {
+    input: R,
+    shared: Table,
+    chunk_size: usize,
+    threads: usize,
+    /// decoded data of the current batch
+    buffer: Vec<u8>,
+    position: usize,
+    done: bool,
+    crc: Crc32,
+}
+
+impl<R: BufRead> ChunkedDecoder<R> {
+    /// Reads the header from `input` and prepares decoding the chunks using `threads` threads
+    ///
+    /// # Errors
+    /// This method returns an error if `input` does not start with a valid chunked mode header
+    ///
+    /// # Panics
+    /// This method panics if `threads` is 0
+    pub fn new(mut input: R, threads: usize) -> Result<Self, Error> {
+        if header::read_mode(&mut input)? != Mode::Chunked {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file is not encoded in chunks, use hdecode instead",
+            )
+            .into());
+        }
+        ChunkedDecoder::after_mode(input, threads)
+    }
+
+    /// Prepares decoding chunked mode data from `input` that is positioned after the mode
+    pub(crate) fn after_mode(mut input: R, threads: usize) -> Result<Self, Error> {
+        assert!(threads > 0, "at least one thread is needed");
+        let chunk_size = usize::try_from(read_varint(&mut input)?)
+            .ok()
+            .filter(|size| (1..=MAX_CHUNK_SIZE).contains(size))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size"))?;
+        let lengths = Tree::<u8>::load_lengths(&mut input)?;
+        header::read_end(&mut input)?;
+        let shared = Table::from_lengths(&lengths)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "code cannot be decoded"))?;
+        Ok(ChunkedDecoder {
+            input,
+            shared,
+            chunk_size,
+            threads,
+            buffer: Vec::new(),
+            position: 0,
+            done: false,
+            crc: Crc32::new(),
+        })
+    }
+
+    /// Reads the header and payload of the next chunk
+    ///
+    /// # Returns
+    /// [None] if the end marker was reached
+    fn read_chunk(&mut self) -> Result<Option<Chunk>, Error> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut kind = [0u8];
+        self.input.read_exact(&mut kind)?;
+        let lengths = match kind[0] {
+            END => return Ok(None),
+            SHARED_TREE => None,
+            OWN_TREE => Some(Tree::<u8>::load_lengths(&mut self.input)?),
+            _ => return Err(invalid("unknown chunk kind").into()),
+        };
+        let len = read_varint(&mut self.input)?;
+        if len == 0 || len > self.chunk_size as u64 {
+            return Err(invalid("chunk is larger than the chunk size").into());
+        }
+        let payload_len = read_varint(&mut self.input)?;
+        // every byte takes at least one bit, checked before the chunk is allocated
+        if len > payload_len.saturating_mul(8) {
+            return Err(invalid("chunk is larger than its payload").into());
+        }
+        let mut payload = Vec::new();
+        (&mut self.input)
+            .take(payload_len)
+            .read_to_end(&mut payload)?;
+        if payload.len() as u64 != payload_len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(Some(Chunk {
+            lengths,
+            len: len as usize,
+            payload,
+        }))
+    }
+
+    /// Reads the next chunks, one per thread, and decodes them in parallel
+    fn next_batch(&mut self) -> Result<(), Error> {
+        let mut chunks = Vec::with_capacity(self.threads);
+        while chunks.len() < self.threads {
+            match self.read_chunk()? {
+                Some(chunk) => chunks.push(chunk),
+                None => {
+                    self.done = true;
+                    break;
+                }
+            }
+        }
+
+        self.buffer.clear();
+        self.buffer
+            .resize(chunks.iter().map(|chunk| chunk.len).sum(), 0);
+        self.position = 0;
+        let shared = &self.shared;
+        thread::scope(|scope| {
+            let mut rest = &mut self.buffer[..];
+            let mut workers = Vec::with_capacity(chunks.len());
+            for chunk in chunks.iter() {
+                let output;
+                (output, rest) = rest.split_at_mut(chunk.len);
+                workers.push(scope.spawn(move || chunk.decode(shared, output)));
+            }
+            workers.into_iter().try_for_each(join)
+        })?;
+
+        self.crc.update(&self.buffer);
+        if self.done {
+            self.crc.verify(read_trailer(&mut self.input)?)?;
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for ChunkedDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.buffer.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.next_batch()?;
+        }
+        let read = (&self.buffer[self.position..]).read(buf)?;
+        self.position += read;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{hdecode, hencode};
+
+    fn roundtrip(raw: &[u8], chunk_size: usize) -> Vec<u8> {
+        let mut coded = Vec::new();
+        hencode_chunked(&mut Cursor::new(raw), &mut coded, chunk_size, 4).expect("encoding failed");
+        let mut out = Vec::new();
+        hdecode(&coded[..], &mut out).expect("decoding failed");
+        assert_eq!(raw, out);
+
+        for threads in [1, 3] {
+            let mut out = Vec::new();
+            ChunkedDecoder::new(&coded[..], threads)
+                .expect("invalid header")
+                .read_to_end(&mut out)
+                .expect("decoding failed");
+            assert_eq!(raw, out);
+        }
+        coded
+    }
+
+    #[test]
+    fn deterministic() {
+        let raw = include_bytes!("chunked.rs");
+        let coded = roundtrip(raw, 1000);
+        for threads in [1, 2, 7, 64] {
+            let mut other = Vec::new();
+            hencode_chunked(&mut Cursor::new(raw), &mut other, 1000, threads)
+                .expect("encoding failed");
+            assert_eq!(coded, other);
+        }
+    }
+
+    #[test]
+    fn own_trees() {
+        // text followed by binary data, the chunks of each part are smaller with their own tree
+        let mut raw = b"the quick brown fox jumps over the lazy dog. ".repeat(200);
+        raw.extend((0..=u8::MAX).cycle().take(9000).map(|byte| byte / 16 + 200));
+        let coded = roundtrip(&raw, 3000);
+        let mut single = Vec::new();
+        hencode(&mut &raw[..], &mut single).expect("encoding failed");
+        assert!(coded.len() < single.len());
+    }
+
+    #[test]
+    fn edge_cases() {
+        roundtrip(&[], 10);
+        roundtrip(&[7], 10);
+        roundtrip(&[7; 10], 10);
+        roundtrip(&[7; 11], 10);
+        roundtrip(b"single byte chunks", 1);
+    }
+
+    #[test]
+    fn corrupted() {
+        let raw = b"every chunk only depends on the header. ".repeat(50);
+        let coded = roundtrip(&raw, 500);
+        // the trailer follows the end marker
+        let mut truncated = coded[..coded.len() - 5].to_vec();
+        assert!(hdecode(&truncated[..], Vec::new()).is_err());
+        truncated.truncate(coded.len() / 2);
+        assert!(hdecode(&truncated[..], Vec::new()).is_err());
+    }
+
+    #[test]
+    fn oversized_chunk() {
+        let coded = |chunk_size: u64, len: u64| {
+            let mut coded = Vec::new();
+            header::write_mode(&mut coded, Mode::Chunked).expect("io err");
+            write_varint(&mut coded, chunk_size).expect("io err");
+            Tree::mktree(leaves(&[1; 256]))
+                .store_lengths(&mut coded)
+                .expect("io err");
+            coded.extend(HEADER_END);
+            coded.push(SHARED_TREE);
+            write_varint(&mut coded, len).expect("io err");
+            write_varint(&mut coded, 4).expect("io err");
+            coded.extend([0; 4]);
+            coded
+        };
+        // the sizes are checked before the chunk is allocated
+        assert!(hdecode(&coded(1 << 40, 1 << 40)[..], Vec::new()).is_err());
+        assert!(hdecode(&coded(1 << 20, 33)[..], Vec::new()).is_err());
+        assert!(hdecode(&coded(1 << 20, 4)[..], Vec::new()).is_err());
+    }
+
+    #[test]
+    fn padded_payload() {
+        let raw = b"a single chunk, padded with a few bytes. ".repeat(10);
+        let (counts, crc) = count(&mut Cursor::new(&raw)).expect("io err");
+        let tree = Tree::mktree(leaves(&counts));
+        let mut payload = Vec::new();
+        encode_payload(&tree, &mut &raw[..], &mut payload).expect("io err");
+        let coded = |extra: usize| {
+            let mut coded = Vec::new();
+            header::write_mode(&mut coded, Mode::Chunked).expect("io err");
+            write_varint(&mut coded, raw.len() as u64).expect("io err");
+            tree.store_lengths(&mut coded).expect("io err");
+            coded.extend(HEADER_END);
+            coded.push(SHARED_TREE);
+            write_varint(&mut coded, raw.len() as u64).expect("io err");
+            write_varint(&mut coded, (payload.len() + extra) as u64).expect("io err");
+            coded.extend(&payload);
+            coded.extend(vec![0; extra]);
+            coded.push(END);
+            write_trailer(&mut coded, &crc).expect("io err");
+            coded
+        };
+        hdecode(&coded(0)[..], Vec::new()).expect("decoding failed");
+        for extra in 1..8 {
+            let err = hdecode(&coded(extra)[..], Vec::new()).expect_err("payload is padded");
+            assert!(matches!(err, Error::Overlong { .. }), "{extra}: {err}");
+        }
+    }
+}
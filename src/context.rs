@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::io::{self, prelude::*, BufReader, SeekFrom};
+
+use crate::bitutils::{read_varint, write_varint, Symbol};
+use crate::checksum::{write_trailer, Crc32};
+use crate::decoder::Payload;
+use crate::header::{self, Mode, HEADER_END};
+use crate::table::Table;
+use crate::tree::Tree;
+use crate::{leaves, payload_bits, Encoder, Error, FLUSH_THRESHOLD};
+
+/// Context of the first byte, which has no preceding byte
+const INITIAL_CONTEXT: u8 = 0;
+
+/// Encodes `input` into `output` using one tree per preceding byte (order-1 context).
+///
+/// `input` is spooled through an [`Encoder`] first, use [`hencode_context_seekable`]
+/// if it is seekable already.
+pub fn hencode_context(input: &mut impl Read, output: &mut impl Write) -> Result<(), Error> {
+    let mut encoder = Encoder::with_context(output);
+    io::copy(input, &mut encoder)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+/// Encodes `input` starting at its current position into `output` using one tree per
+/// preceding byte (order-1 context).
+///
+/// Contexts whose own tree would not pay for the space it takes in the header share
+/// a fallback tree, contexts that never occur are dropped.
+///
+/// The header stores the code lengths of the fallback tree, a bitmap of the contexts
+/// with their own tree and the code lengths of those trees in order of their context,
+/// followed by the number of bytes and the size of the payload.
+pub fn hencode_context_seekable(
+    input: &mut (impl Read + Seek),
+    output: &mut impl Write,
+) -> Result<(), Error> {
+    let start = input.stream_position()?;
+    let (counts, crc) = count_pairs(input)?;
+    input.seek(SeekFrom::Start(start))?;
+
+    let mut total = [0usize; 256];
+    for context in counts.iter() {
+        for (sum, count) in total.iter_mut().zip(context) {
+            *sum += count;
+        }
+    }
+    let order0 = Tree::mktree(leaves(&total)).code_lengths();
+
+    let mut own = Vec::new();
+    let mut shared = [0usize; 256];
+    for (context, counts) in counts.iter().enumerate() {
+        if counts.iter().all(|&count| count == 0) {
+            continue;
+        }
+        let tree = Tree::mktree(leaves(counts));
+        let mut lengths = Vec::new();
+        tree.store_lengths(&mut lengths)?;
+        let own_bits = lengths.len() * 8
+            + payload_bits(&tree.code_lengths(), counts).expect("tree contains all bytes");
+        let shared_bits = payload_bits(&order0, counts).expect("tree contains all bytes");
+        if own_bits < shared_bits {
+            own.push((context, tree));
+        } else {
+            for (sum, count) in shared.iter_mut().zip(counts) {
+                *sum += count;
+            }
+        }
+    }
+    let fallback = Tree::mktree(leaves(&shared));
+
+    header::write_mode(output, Mode::Context)?;
+    fallback.store_lengths(output)?;
+    let mut bitmap = [0u8; 32];
+    for &(context, _) in own.iter() {
+        bitmap[context / 8] |= 128 >> (context % 8);
+    }
+    output.write_all(&bitmap)?;
+    for (_, tree) in own.iter() {
+        tree.store_lengths(output)?;
+    }
+    let len: usize = counts.iter().flatten().sum();
+    let mut bits =
+        payload_bits(&fallback.code_lengths(), &shared).expect("tree contains all bytes");
+    for (context, tree) in own.iter() {
+        bits +=
+            payload_bits(&tree.code_lengths(), &counts[*context]).expect("tree contains all bytes");
+    }
+    write_varint(output, len as u64)?;
+    write_varint(output, bits.div_ceil(8) as u64)?;
+    output.write_all(HEADER_END)?;
+
+    let mut maps = vec![fallback.make_conversion_map()];
+    let mut select = [0usize; 256];
+    for (context, tree) in own.iter() {
+        select[*context] = maps.len();
+        maps.push(tree.make_conversion_map());
+    }
+
+    encode_payload(&maps, &select, input, output)?;
+    write_trailer(output, &crc)?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Writes the encoded bytes of `input`, choosing the map of each byte by its predecessor
+fn encode_payload(
+    maps: &[HashMap<u8, Symbol>],
+    select: &[usize; 256],
+    input: &mut impl Read,
+    output: &mut impl Write,
+) -> Result<(), Error> {
+    let mut encoded = Symbol {
+        bytes: Vec::with_capacity(FLUSH_THRESHOLD + 1),
+        bitpos: 0,
+        bytepos: 0,
+    };
+    let mut previous = INITIAL_CONTEXT;
+    let mut reader = BufReader::new(input);
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        for &byte in buf {
+            encoded.append_sym(&maps[select[previous as usize]][&byte]);
+            previous = byte;
+            if encoded.bytepos >= FLUSH_THRESHOLD {
+                encoded.write_complete(output)?;
+            }
+        }
+        let len = buf.len();
+        reader.consume(len);
+    }
+    output.write_all(&encoded.bytes)?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Counts all bytes from the current position of `input` by their preceding byte and
+/// computes their checksum
+fn count_pairs(input: &mut impl Read) -> Result<(Vec<[usize; 256]>, Crc32), Error> {
+    let mut counts = vec![[0usize; 256]; 256];
+    let mut crc = Crc32::new();
+    let mut previous = INITIAL_CONTEXT;
+    let mut reader = BufReader::new(input);
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        for &byte in buf {
+            counts[previous as usize][byte as usize] += 1;
+            previous = byte;
+        }
+        crc.update(buf);
+        let len = buf.len();
+        reader.consume(len);
+    }
+    Ok((counts, crc))
+}
+
+/// Pull based decoder for data encoded by [`hencode_context`].
+///
+/// Picks the table of each byte based on the byte decoded before it.
+pub struct ContextDecoder<R: BufRead> {
+    /// the fallback table followed by the tables of contexts with their own tree
+    tables: Vec<Table>,
+    /// index into `tables` for every context
+    select: [usize; 256],
+    previous: u8,
+    payload: Payload<R>,
+}
+
+impl<R: BufRead> ContextDecoder<R> {
+    /// Reads the header from `input` and prepares decoding the payload
+    ///
+    /// # Errors
+    /// This method returns an error if `input` does not start with a valid context mode header
+    pub fn new(mut input: R) -> Result<Self, Error> {
+        if header::read_mode(&mut input)? != Mode::Context {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file does not use context modeling, use hdecode instead",
+            )
+            .into());
+        }
+        ContextDecoder::after_mode(input)
+    }
+
+    /// Prepares decoding context mode data from `input` that is positioned after the mode
+    pub(crate) fn after_mode(mut input: R) -> Result<Self, Error> {
+        let load = |input: &mut R| -> Result<Table, Error> {
+            let lengths = Tree::<u8>::load_lengths(input)?;
+            Ok(Table::from_lengths(&lengths).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "code cannot be decoded")
+            })?)
+        };
+        let mut tables = vec![load(&mut input)?];
+        let mut bitmap = [0u8; 32];
+        input.read_exact(&mut bitmap)?;
+        let mut select = [0usize; 256];
+        for (context, table) in select.iter_mut().enumerate() {
+            if bitmap[context / 8] & (128 >> (context % 8)) != 0 {
+                *table = tables.len();
+                tables.push(load(&mut input)?);
+            }
+        }
+        let len = read_varint(&mut input)?;
+        let size = read_varint(&mut input)?;
+        header::read_end(&mut input)?;
+
+        Ok(ContextDecoder {
+            tables,
+            select,
+            previous: INITIAL_CONTEXT,
+            payload: Payload::new(input, len, Some(size), true)?,
+        })
+    }
+
+    /// Number of bytes the payload decodes to, useful to preallocate or report progress
+    pub fn original_len(&self) -> u64 {
+        self.payload.len()
+    }
+
+    fn decode_byte(&mut self) -> Result<u8, Error> {
+        let table = &self.tables[self.select[self.previous as usize]];
+        let byte = self.payload.decode(table)?;
+        self.previous = byte;
+        Ok(byte)
+    }
+}
+
+impl<R: BufRead> Read for ContextDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut written = 0;
+        while written < buf.len() && !self.payload.done() {
+            buf[written] = self.decode_byte()?;
+            written += 1;
+        }
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hdecode, hencode};
+
+    const SOURCE: &str = include_str!("context.rs");
+
+    fn roundtrip(raw: &[u8]) -> Vec<u8> {
+        let mut coded = Vec::new();
+        hencode_context(&mut &raw[..], &mut coded).expect("encoding failed");
+        let mut out = Vec::new();
+        hdecode(&coded[..], &mut out).expect("decoding failed");
+        assert_eq!(raw, out);
+
+        let mut out = Vec::new();
+        ContextDecoder::new(&coded[..])
+            .expect("invalid header")
+            .read_to_end(&mut out)
+            .expect("decoding failed");
+        assert_eq!(raw, out);
+        coded
+    }
+
+    #[test]
+    fn source_code() {
+        let coded = roundtrip(SOURCE.as_bytes());
+        let mut order0 = Vec::new();
+        hencode(&mut SOURCE.as_bytes(), &mut order0).expect("encoding failed");
+        assert!(coded.len() < order0.len());
+    }
+
+    #[test]
+    fn edge_cases() {
+        roundtrip(&[]);
+        roundtrip(&[0]);
+        roundtrip(&[1; 100]);
+        roundtrip(b"ab");
+        roundtrip(&(0..=u8::MAX).collect::<Vec<_>>());
+    }
+}
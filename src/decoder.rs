@@ -0,0 +1,429 @@
+use std::borrow::Borrow;
+use std::io::{self, prelude::*, SeekFrom};
+use std::marker::PhantomData;
+
+use crate::bitutils::read_varint;
+use crate::checksum::{read_window_trailer, Crc32};
+use crate::header::{self, Mode};
+use crate::index::SeekIndex;
+use crate::table::Table;
+use crate::tree::{Key, Tree};
+use crate::window::BitWindow;
+use crate::Error;
+
+/// Pull based decoder wrapping a [`BufRead`] that contains encoded data.
+///
+/// The tree header is read when the decoder is constructed, the payload is decoded
+/// lazily as bytes are requested through [`Read`]. Decoding stops after the number of
+/// keys stored in front of the payload, data following the encoded file is not read.
+/// Encoded files also store the size of the payload, payloads that continue after the
+/// last key are reported as [`Error::Overlong`].
+///
+/// The table used for decoding is either owned by the decoder or borrowed through `T`
+/// when decoding headerless payloads with [`Decoder::with_table`].
+///
+/// Decoders for keys other than bytes (see [`Key`]) yield the decoded keys as [`Iterator`].
+///
+/// The checksum following the payload of encoded files is verified once the last key
+/// was decoded.
+///
+/// Decoders created by [`Decoder::seekable`] implement [`Seek`] over the decoded data.
+pub struct Decoder<R: BufRead, T: Borrow<Table<K>> = Table, K: Key = u8> {
+    table: T,
+    payload: Payload<R>,
+    /// checkpoints to seek to, only set by [`Decoder::seekable`]
+    index: Option<SeekIndex>,
+    /// byte offset of the payload in the reader, only meaningful with `index`
+    start: u64,
+    key: PhantomData<K>,
+}
+
+impl<R: BufRead> Decoder<R> {
+    /// Reads the tree header from `input` and prepares decoding the payload
+    ///
+    /// # Errors
+    /// This method returns an error if `input` does not start with a valid header
+    pub fn new(mut input: R) -> Result<Self, Error> {
+        let mode = header::read_mode(&mut input)?;
+        let (table, len, size, _) = Decoder::read_header(&mut input, mode)?;
+        Decoder::with_trailer(table, input, len, size)
+    }
+
+    /// Prepares decoding static or indexed mode data from `input` that is positioned
+    /// after `mode`
+    pub(crate) fn after_mode(mut input: R, mode: Mode) -> Result<Self, Error> {
+        let (table, len, size, _) = Decoder::read_header(&mut input, mode)?;
+        Decoder::with_trailer(table, input, len, size)
+    }
+
+    /// Reads the rest of a static or indexed mode header following `mode`
+    ///
+    /// # Returns
+    /// The table, the number of keys, the size of the payload and the seek index
+    fn read_header(input: &mut R, mode: Mode) -> Result<(Table, u64, u64, SeekIndex), Error> {
+        let lengths = Tree::<u8>::load_lengths(input)?;
+        let len = read_varint(input)?;
+        let size = read_varint(input)?;
+        let index = match mode {
+            Mode::Static => SeekIndex::start_only(),
+            Mode::Indexed => SeekIndex::load(input, len, size)?,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "file does not use a single tree, use hdecode instead",
+                )
+                .into())
+            }
+        };
+        header::read_end(input)?;
+        let table = Table::from_lengths(&lengths)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "code cannot be decoded"))?;
+        Ok((table, len, size, index))
+    }
+}
+
+impl<R: BufRead + Seek> Decoder<R> {
+    /// Reads the tree header from `input` and prepares decoding the payload, which can be
+    /// seeked through [`Seek`].
+    ///
+    /// Files written by [`hencode_indexed`](crate::hencode_indexed) are seeked to the
+    /// closest checkpoint before the position, other files have to be decoded from the
+    /// start of the payload. The checksum is only verified if the data is decoded from
+    /// the start to the end without seeking to a checkpoint in between.
+    ///
+    /// # Errors
+    /// This method returns an error if `input` does not start with a valid static or
+    /// indexed mode header
+    pub fn seekable(mut input: R) -> Result<Self, Error> {
+        let mode = header::read_mode(&mut input)?;
+        let (table, len, size, index) = Decoder::read_header(&mut input, mode)?;
+        let start = input.stream_position()?;
+        Ok(Decoder {
+            index: Some(index),
+            start,
+            ..Decoder::with_trailer(table, input, len, size)?
+        })
+    }
+}
+
+impl<R: BufRead, T: Borrow<Table<K>>, K: Key> Decoder<R, T, K> {
+    /// Prepares decoding a payload without tree header from `input` using `table`
+    ///
+    /// The payload starts with the number of encoded keys as varint.
+    pub fn with_table(table: T, mut input: R) -> Result<Self, Error> {
+        let len = read_varint(&mut input)?;
+        Decoder::build(table, input, len, None, false)
+    }
+
+    /// Prepares decoding `len` keys from `input` using `table`
+    pub(crate) fn with_len(table: T, input: R, len: u64) -> Result<Self, Error> {
+        Decoder::build(table, input, len, None, false)
+    }
+
+    /// Prepares decoding `len` keys from `input` using `table`, which take `size` bytes
+    pub(crate) fn with_size(table: T, input: R, len: u64, size: u64) -> Result<Self, Error> {
+        Decoder::build(table, input, len, Some(size), false)
+    }
+
+    /// Prepares decoding `len` keys from `input` using `table`, which take `size` bytes
+    /// and are followed by the checksum trailer
+    pub(crate) fn with_trailer(table: T, input: R, len: u64, size: u64) -> Result<Self, Error> {
+        Decoder::build(table, input, len, Some(size), true)
+    }
+
+    fn build(
+        table: T,
+        input: R,
+        len: u64,
+        size: Option<u64>,
+        trailer: bool,
+    ) -> Result<Self, Error> {
+        Ok(Decoder {
+            table,
+            payload: Payload::new(input, len, size, trailer)?,
+            index: None,
+            start: 0,
+            key: PhantomData,
+        })
+    }
+
+    /// Number of keys the payload decodes to, useful to preallocate or report progress
+    pub fn original_len(&self) -> u64 {
+        self.payload.len
+    }
+
+    /// Returns the wrapped reader.
+    ///
+    /// The reader may have been advanced a few bytes past the payload, unless
+    /// the payload ends together with the reader.
+    pub fn into_inner(self) -> R {
+        self.payload.window.into_inner()
+    }
+
+    fn decode_key(&mut self) -> Result<K, Error> {
+        self.payload.decode(self.table.borrow())
+    }
+}
+
+/// Position in a payload of a known number of keys, shared by the decoders reading
+/// one payload through a [`BitWindow`]
+pub(crate) struct Payload<R: BufRead> {
+    window: BitWindow<R>,
+    /// number of keys in the payload
+    len: u64,
+    decoded: u64,
+    done: bool,
+    /// number of bytes of the payload, if it was stored
+    size: Option<u64>,
+    /// checksum of the keys decoded so far, if the payload is followed by a trailer
+    crc: Option<Crc32>,
+    /// [`BitWindow::bytes_consumed`] at the start of the payload
+    origin: u64,
+}
+
+impl<R: BufRead> Payload<R> {
+    /// Prepares reading `len` keys from `input`, if the payload `size` is given the keys
+    /// have to take exactly that many bytes, with `trailer` they are followed by the
+    /// checksum trailer
+    pub(crate) fn new(
+        mut input: R,
+        len: u64,
+        size: Option<u64>,
+        trailer: bool,
+    ) -> Result<Self, Error> {
+        // the window would hallucinate a byte for empty payloads
+        if len > 0 && input.fill_buf()?.is_empty() {
+            return Err(Error::Truncated {
+                expected: len,
+                decoded: 0,
+            });
+        }
+        let mut payload = Payload {
+            window: BitWindow::from(input),
+            len,
+            decoded: 0,
+            done: len == 0,
+            size,
+            crc: trailer.then(Crc32::new),
+            origin: 0,
+        };
+        if payload.done {
+            payload.finish()?;
+        }
+        Ok(payload)
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether all keys were decoded
+    pub(crate) fn done(&self) -> bool {
+        self.done
+    }
+
+    /// Decodes the next key using `table`, checking the end of the payload after the last one
+    pub(crate) fn decode<K: Key>(&mut self, table: &Table<K>) -> Result<K, Error> {
+        let key = match table.decode(&mut self.window) {
+            Err(Error::NoBits) => {
+                return Err(Error::Truncated {
+                    expected: self.len,
+                    decoded: self.decoded,
+                })
+            }
+            key => key?,
+        };
+        self.decoded += 1;
+        if let Some(crc) = self.crc.as_mut() {
+            crc.update_key(key);
+        }
+        if self.decoded == self.len {
+            self.done = true;
+            self.finish()?;
+        }
+        Ok(key)
+    }
+
+    /// Checks that the payload ends with the last key, with the bits up to the next byte
+    /// being zero as they are written by the encoders, and compares the checksum of the
+    /// decoded keys with the trailer, if there is one
+    fn finish(&mut self) -> Result<(), Error> {
+        let rest = self.window.initialized() % 8;
+        if rest > 0 && self.window.show(rest) != 0 {
+            return Err(Error::Overlong { expected: self.len });
+        }
+        if let Some(size) = self.size {
+            let consumed = self.window.bytes_consumed() - self.origin;
+            if consumed < size {
+                return Err(Error::Overlong { expected: self.len });
+            }
+            if consumed > size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the keys take more bytes than the stored payload size",
+                )
+                .into());
+            }
+        }
+        match self.crc {
+            Some(crc) => crc.verify(read_window_trailer(&mut self.window)?),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<R: BufRead, T: Borrow<Table>> Read for Decoder<R, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut written = 0;
+        while written < buf.len() && !self.payload.done {
+            buf[written] = self.decode_key()?;
+            written += 1;
+        }
+        Ok(written)
+    }
+}
+
+impl<R: BufRead + Seek> Seek for Decoder<R> {
+    /// Seeks to a position of the decoded data, jumping to the closest checkpoint before
+    /// it and decoding forward from there.
+    ///
+    /// Seeking past the end of the decoded data fails.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let Some(index) = self.index.as_ref() else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "only decoders created by Decoder::seekable can seek",
+            ));
+        };
+        let target = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::Current(offset) => self.payload.decoded.checked_add_signed(offset),
+            SeekFrom::End(offset) => self.payload.len.checked_add_signed(offset),
+        }
+        .filter(|&target| target <= self.payload.len)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "position is outside of the data",
+            )
+        })?;
+
+        let (checkpoint, offset) = index.checkpoint(target);
+        let payload = &mut self.payload;
+        if target < payload.decoded || checkpoint > payload.decoded {
+            payload.window.seek_bit(self.start * 8 + offset)?;
+            // the window counts bytes from the start of the reader after seeking
+            payload.origin = self.start;
+            payload.decoded = checkpoint;
+            payload.done = checkpoint == payload.len;
+            // the checksum covers all data, it can only be computed when starting at 0
+            payload.crc = (checkpoint == 0).then(Crc32::new);
+        }
+        while self.payload.decoded < target {
+            self.decode_key()?;
+        }
+        Ok(target)
+    }
+}
+
+impl<R: BufRead, T: Borrow<Table<K>>, K: Key> Iterator for Decoder<R, T, K> {
+    type Item = Result<K, Error>;
+
+    /// Decodes the next key, decoding stops after the first error
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.payload.done {
+            return None;
+        }
+        let key = self.decode_key();
+        if key.is_err() {
+            self.payload.done = true;
+        }
+        Some(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{prelude::*, BufReader};
+
+    use super::Decoder;
+    use crate::header::HEADER_END;
+    use crate::{hencode, Error};
+
+    #[test]
+    fn lines() {
+        let raw = "first line\nsecond line\n\nlast line";
+        let mut coded = Vec::new();
+        hencode(&mut raw.as_bytes(), &mut coded).expect("encoding failed");
+
+        let decoder = Decoder::new(&coded[..]).expect("invalid header");
+        let lines: Vec<String> = BufReader::new(decoder)
+            .lines()
+            .collect::<Result<_, _>>()
+            .expect("decoding failed");
+        assert_eq!(vec!["first line", "second line", "", "last line"], lines);
+    }
+
+    #[test]
+    fn small_reads() {
+        let raw = b"reading one byte at a time must yield the same data";
+        let mut coded = Vec::new();
+        hencode(&mut &raw[..], &mut coded).expect("encoding failed");
+
+        let mut decoder = Decoder::new(&coded[..]).expect("invalid header");
+        let mut out = Vec::new();
+        let mut byte = [0u8];
+        while decoder.read(&mut byte).expect("decoding failed") == 1 {
+            out.push(byte[0]);
+        }
+        assert_eq!(&raw[..], &out);
+    }
+
+    #[test]
+    fn stored_length() {
+        let raw = b"abracadabra";
+        let mut coded = Vec::new();
+        hencode(&mut &raw[..], &mut coded).expect("encoding failed");
+        // the checksum trailer follows the last payload byte
+        let last = coded.len() - 5;
+
+        let mut input = coded.clone();
+        input.extend(b"trailing data");
+        let mut decoder = Decoder::new(&input[..]).expect("invalid header");
+        assert_eq!(raw.len() as u64, decoder.original_len());
+        let mut out = Vec::new();
+        decoder.read_to_end(&mut out).expect("decoding failed");
+        assert_eq!(&raw[..], &out);
+
+        let mut out = Vec::new();
+        let err = Decoder::new(&coded[..last])
+            .expect("invalid header")
+            .read_to_end(&mut out)
+            .expect_err("payload is truncated");
+        assert!(matches!(
+            Error::from(err),
+            Error::Truncated { expected: 11, .. }
+        ));
+
+        // 23 bits of payload are padded by a single bit
+        let mut input = coded.clone();
+        input[last] |= 1;
+        let err = Decoder::new(&input[..])
+            .expect("invalid header")
+            .read_to_end(&mut out)
+            .expect_err("payload is too long");
+        assert!(matches!(Error::from(err), Error::Overlong { expected: 11 }));
+
+        // a payload of 4 bytes whose last byte is not needed by the 11 keys
+        let mut input = coded.clone();
+        let size = last - HEADER_END.len() - 3;
+        assert_eq!(3, input[size]);
+        input[size] = 4;
+        input.insert(last + 1, 0);
+        let err = Decoder::new(&input[..])
+            .expect("invalid header")
+            .read_to_end(&mut out)
+            .expect_err("payload is too long");
+        assert!(matches!(Error::from(err), Error::Overlong { expected: 11 }));
+    }
+}
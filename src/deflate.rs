@@ -0,0 +1,494 @@
+use std::io::prelude::*;
+
+use crate::bitutils::Symbol;
+use crate::checksum::{Adler32, Crc32};
+use crate::inflate::{
+    fixed_lengths, CODE_LENGTH_ORDER, DEFLATE, DISTANCES, FIRST_LENGTH, GZIP_MAGIC,
+};
+use crate::lz77::{
+    code_of, tokenize, Token, DISTANCE_BASE, DISTANCE_EXTRA, END_OF_BLOCK, LENGTH_BASE,
+    LENGTH_EXTRA, WINDOW_SIZE,
+};
+use crate::tree::{canonical_codes, Tree};
+use crate::Error;
+
+/// Number of input bytes per block
+const BLOCK_SIZE: usize = 64 * 1024;
+/// Maximum length of a stored block
+const MAX_STORED: usize = u16::MAX as usize;
+/// Maximum code length of literal/length and distance codes
+const MAX_CODE_LEN: u8 = 15;
+/// Maximum code length of the code length code
+const MAX_LENGTH_CODE_LEN: u8 = 7;
+/// Number of literal/length and distance symbols that can be used
+const USED_LITERALS: usize = 286;
+const USED_DISTANCES: usize = 30;
+
+const STORED: u32 = 0;
+const FIXED: u32 = 1;
+const DYNAMIC: u32 = 2;
+
+/// Operating system field of gzip headers for unknown systems
+const OS_UNKNOWN: u8 = 255;
+
+/// Encodes `input` into `output` as raw DEFLATE data (RFC 1951) without any framing.
+///
+/// `level` goes from 0 (no matches, fastest) to 9 (most thorough search). Every block
+/// is written with dynamic codes, the fixed codes or stored, whichever is smallest.
+///
+/// # Panics
+/// This function panics if `level` is larger than 9
+pub fn hencode_deflate(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    level: u8,
+) -> Result<(), Error> {
+    deflate(input, output, level, |_| ())?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Encodes `input` into `output` as a gzip file (RFC 1952) that can be read by `gzip`.
+///
+/// See [`hencode_deflate`] for `level`. The header stores no file name and no
+/// modification time.
+///
+/// # Panics
+/// This function panics if `level` is larger than 9
+pub fn hencode_gzip(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    level: u8,
+) -> Result<(), Error> {
+    // extra flags for the slowest and the fastest level
+    let extra = match level {
+        9 => 2,
+        1 => 4,
+        _ => 0,
+    };
+    output.write_all(&GZIP_MAGIC)?;
+    output.write_all(&[DEFLATE, 0, 0, 0, 0, 0, extra, OS_UNKNOWN])?;
+    let mut crc = Crc32::new();
+    let mut size = 0u32;
+    deflate(input, output, level, |data| {
+        crc.update(data);
+        size = size.wrapping_add(data.len() as u32);
+    })?;
+    output.write_all(&crc.sum().to_le_bytes())?;
+    output.write_all(&size.to_le_bytes())?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Encodes `input` into `output` as a zlib stream (RFC 1950).
+///
+/// See [`hencode_deflate`] for `level`.
+///
+/// # Panics
+/// This function panics if `level` is larger than 9
+pub fn hencode_zlib(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    level: u8,
+) -> Result<(), Error> {
+    // deflate with a window of 32 KiB
+    let method = 0x78u8;
+    let flags = match level {
+        0 | 1 => 0,
+        2..=5 => 1,
+        6 => 2,
+        _ => 3,
+    } << 6;
+    let check = 31 - ((method as u16) << 8 | flags as u16) % 31;
+    output.write_all(&[method, flags | (check % 31) as u8])?;
+    let mut adler = Adler32::new();
+    deflate(input, output, level, |data| adler.update(data))?;
+    output.write_all(&adler.sum().to_be_bytes())?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Writes the DEFLATE blocks of `input` into `output`, passing the data of every block
+/// to `observe`
+fn deflate(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    level: u8,
+    mut observe: impl FnMut(&[u8]),
+) -> Result<(), Error> {
+    assert!(level <= 9, "levels go from 0 to 9");
+    let mut data = Vec::with_capacity(WINDOW_SIZE + BLOCK_SIZE);
+    // the block after the current one, to know which block is the last
+    let mut next = Vec::with_capacity(BLOCK_SIZE);
+    input
+        .by_ref()
+        .take(BLOCK_SIZE as u64)
+        .read_to_end(&mut next)?;
+    let mut encoded = Symbol {
+        bytes: Vec::new(),
+        bitpos: 0,
+        bytepos: 0,
+    };
+    loop {
+        // keep the end of the previous block as history
+        let start = data.len().min(WINDOW_SIZE);
+        data.drain(..data.len() - start);
+        data.append(&mut next);
+        input
+            .by_ref()
+            .take(BLOCK_SIZE as u64)
+            .read_to_end(&mut next)?;
+        let last = next.is_empty();
+
+        observe(&data[start..]);
+        let tokens = tokenize(&data, start, level);
+        write_block(&mut encoded, &data[start..], &tokens, last);
+        encoded.write_complete_lsb_first(output)?;
+        if last {
+            break;
+        }
+    }
+    encoded.pad_to_byte();
+    encoded.write_complete_lsb_first(output)?;
+    Ok(())
+}
+
+/// Appends the smallest encoding of the block containing `data` to `output`.
+///
+/// `tokens` are the matches and literals of `data`.
+fn write_block(output: &mut Symbol, data: &[u8], tokens: &[Token], last: bool) {
+    let mut literals = [0usize; USED_LITERALS];
+    let mut distances = [0usize; USED_DISTANCES];
+    // extra bits of lengths and distances, identical for fixed and dynamic codes
+    let mut extra = 0;
+    literals[END_OF_BLOCK as usize] = 1;
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => literals[byte as usize] += 1,
+            Token::Match { length, distance } => {
+                let (length, _) = code_of(&LENGTH_BASE, length);
+                let (distance, _) = code_of(&DISTANCE_BASE, distance);
+                literals[FIRST_LENGTH as usize + length] += 1;
+                distances[distance] += 1;
+                extra += (LENGTH_EXTRA[length] + DISTANCE_EXTRA[distance]) as usize;
+            }
+        }
+    }
+
+    let dynamic = DynamicHeader::new(&literals, &distances);
+    let dynamic_bits = dynamic.bits()
+        + payload_bits(&dynamic.literals, &literals)
+        + payload_bits(&dynamic.distances, &distances)
+        + extra;
+    let fixed = fixed_lengths();
+    let fixed_bits =
+        3 + payload_bits(&fixed, &literals) + payload_bits(&[5; DISTANCES], &distances) + extra;
+    // padding of the first block, every following one starts on a byte boundary
+    let padding = (8 - (output.bits() + 3) % 8) % 8;
+    let chunks = data.len().div_ceil(MAX_STORED);
+    // empty data is never stored
+    let stored_bits = (chunks > 0).then(|| padding + chunks * (3 + 32 + 5) - 5 + 8 * data.len());
+
+    if stored_bits.is_some_and(|bits| bits < dynamic_bits.min(fixed_bits)) {
+        let mut chunks = data.chunks(MAX_STORED).peekable();
+        while let Some(chunk) = chunks.next() {
+            output.append_bits_lsb_first((last && chunks.peek().is_none()) as u32, 1);
+            output.append_bits_lsb_first(STORED, 2);
+            output.pad_to_byte();
+            output.append_bits_lsb_first(chunk.len() as u32, 16);
+            output.append_bits_lsb_first(!(chunk.len() as u32), 16);
+            for &byte in chunk {
+                output.append_bits_lsb_first(byte as u32, 8);
+            }
+        }
+        return;
+    }
+
+    output.append_bits_lsb_first(last as u32, 1);
+    if fixed_bits <= dynamic_bits {
+        output.append_bits_lsb_first(FIXED, 2);
+        write_tokens(output, tokens, &fixed, &[5; DISTANCES]);
+    } else {
+        output.append_bits_lsb_first(DYNAMIC, 2);
+        dynamic.write(output);
+        write_tokens(output, tokens, &dynamic.literals, &dynamic.distances);
+    }
+}
+
+/// Number of bits needed for the codes of `counts` using the code lengths `lengths`
+fn payload_bits(lengths: &[u8], counts: &[usize]) -> usize {
+    counts
+        .iter()
+        .zip(lengths)
+        .map(|(&count, &len)| count * len as usize)
+        .sum()
+}
+
+/// Appends the codes of `tokens` followed by the end of block code to `output`
+fn write_tokens(output: &mut Symbol, tokens: &[Token], literals: &[u8], distances: &[u8]) {
+    let codes = |lengths: &[u8]| {
+        let mut codes = vec![None; lengths.len()];
+        for (key, code) in canonical_codes::<u16>(lengths).expect("code is complete") {
+            codes[key as usize] = Some(code);
+        }
+        codes
+    };
+    let literals = codes(literals);
+    let distances = codes(distances);
+    fn code(codes: &[Option<Symbol>], key: usize) -> &Symbol {
+        codes[key].as_ref().expect("every used key has a code")
+    }
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => output.append_sym(code(&literals, byte as usize)),
+            Token::Match { length, distance } => {
+                let (code_num, extra) = code_of(&LENGTH_BASE, length);
+                output.append_sym(code(&literals, FIRST_LENGTH as usize + code_num));
+                output.append_bits_lsb_first(extra as u32, LENGTH_EXTRA[code_num]);
+                let (code_num, extra) = code_of(&DISTANCE_BASE, distance);
+                output.append_sym(code(&distances, code_num));
+                output.append_bits_lsb_first(extra as u32, DISTANCE_EXTRA[code_num]);
+            }
+        }
+    }
+    output.append_sym(code(&literals, END_OF_BLOCK as usize));
+}
+
+/// Code lengths of a block using dynamic codes and how they are stored
+struct DynamicHeader {
+    literals: Vec<u8>,
+    distances: Vec<u8>,
+    /// number of stored literal/length and distance lengths
+    literal_count: usize,
+    distance_count: usize,
+    /// run length encoded code lengths as symbol and value of the extra bits
+    runs: Vec<(u8, u8)>,
+    length_code: Vec<u8>,
+    /// number of stored lengths of the code length code
+    length_count: usize,
+}
+
+impl DynamicHeader {
+    fn new(literals: &[usize], distances: &[usize]) -> Self {
+        let literals = Tree::<u16>::limited_lengths(literals, MAX_CODE_LEN);
+        let distances = Tree::<u16>::limited_lengths(distances, MAX_CODE_LEN);
+        // trailing unused codes are not stored
+        let used = |lengths: &[u8], min: usize| {
+            lengths
+                .iter()
+                .rposition(|&len| len > 0)
+                .map_or(min, |last| (last + 1).max(min))
+        };
+        let literal_count = used(&literals, FIRST_LENGTH as usize);
+        let distance_count = used(&distances, 1);
+
+        let mut all = literals[..literal_count].to_vec();
+        all.extend_from_slice(&distances[..distance_count]);
+        let runs = run_lengths(&all);
+        let mut counts = [0usize; 19];
+        for &(symbol, _) in runs.iter() {
+            counts[symbol as usize] += 1;
+        }
+        let length_code = Tree::<u16>::limited_lengths(&counts, MAX_LENGTH_CODE_LEN);
+        let length_count = CODE_LENGTH_ORDER
+            .iter()
+            .rposition(|&symbol| length_code[symbol] > 0)
+            .map_or(4, |last| (last + 1).max(4));
+        DynamicHeader {
+            literals,
+            distances,
+            literal_count,
+            distance_count,
+            runs,
+            length_code,
+            length_count,
+        }
+    }
+
+    /// Number of bits of the block header including the block type
+    fn bits(&self) -> usize {
+        let runs: usize = self
+            .runs
+            .iter()
+            .map(|&(symbol, _)| {
+                self.length_code[symbol as usize] as usize + extra_bits(symbol) as usize
+            })
+            .sum();
+        3 + 5 + 5 + 4 + 3 * self.length_count + runs
+    }
+
+    /// Appends everything following the block type to `output`
+    fn write(&self, output: &mut Symbol) {
+        output.append_bits_lsb_first((self.literal_count - FIRST_LENGTH as usize) as u32, 5);
+        output.append_bits_lsb_first(self.distance_count as u32 - 1, 5);
+        output.append_bits_lsb_first(self.length_count as u32 - 4, 4);
+        for &symbol in &CODE_LENGTH_ORDER[..self.length_count] {
+            output.append_bits_lsb_first(self.length_code[symbol] as u32, 3);
+        }
+        let mut codes = vec![None; self.length_code.len()];
+        for (key, code) in canonical_codes::<u16>(&self.length_code).expect("code is complete") {
+            codes[key as usize] = Some(code);
+        }
+        for &(symbol, extra) in self.runs.iter() {
+            output.append_sym(
+                codes[symbol as usize]
+                    .as_ref()
+                    .expect("used symbols have a code"),
+            );
+            output.append_bits_lsb_first(extra as u32, extra_bits(symbol));
+        }
+    }
+}
+
+/// Number of extra bits following a symbol of the code length alphabet
+fn extra_bits(symbol: u8) -> u8 {
+    match symbol {
+        16 => 2,
+        17 => 3,
+        18 => 7,
+        _ => 0,
+    }
+}
+
+/// Encodes `lengths` using the code length alphabet: lengths 0 to 15 are stored as they
+/// are, 16 repeats the previous length 3 to 6 times, 17 and 18 repeat 0 3 to 10 and
+/// 11 to 138 times.
+fn run_lengths(lengths: &[u8]) -> Vec<(u8, u8)> {
+    let mut runs = Vec::new();
+    let mut pos = 0;
+    while pos < lengths.len() {
+        let len = lengths[pos];
+        let run = lengths[pos..]
+            .iter()
+            .take_while(|&&other| other == len)
+            .count();
+        pos += run;
+        let mut left = run;
+        if len == 0 {
+            while left >= 11 {
+                let repeat = left.min(138);
+                runs.push((18, (repeat - 11) as u8));
+                left -= repeat;
+            }
+            if left >= 3 {
+                runs.push((17, (left - 3) as u8));
+                left = 0;
+            }
+        } else {
+            runs.push((len, 0));
+            left -= 1;
+            while left >= 3 {
+                let repeat = left.min(6);
+                runs.push((16, (repeat - 3) as u8));
+                left -= repeat;
+            }
+        }
+        runs.extend((0..left).map(|_| (len, 0)));
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GzDecoder, Inflater, ZlibDecoder};
+
+    const TEXT: &[u8] = include_bytes!("../testdata/deflate/text.txt");
+
+    fn block_type(coded: &[u8]) -> u32 {
+        (coded[0] as u32 >> 1) & 0b11
+    }
+
+    fn roundtrip(raw: &[u8], level: u8) -> Vec<u8> {
+        let mut coded = Vec::new();
+        hencode_deflate(&mut &raw[..], &mut coded, level).expect("encoding failed");
+        let mut out = Vec::new();
+        Inflater::new(&coded[..])
+            .read_to_end(&mut out)
+            .expect("decoding failed");
+        assert_eq!(raw, out);
+        coded
+    }
+
+    #[test]
+    fn block_types() {
+        assert_eq!(DYNAMIC, block_type(&roundtrip(TEXT, 6)));
+        assert_eq!(FIXED, block_type(&roundtrip(b"abc abc abc", 6)));
+        // empty input is a single fixed block with only the end of block code
+        assert_eq!(vec![0x03, 0x00], roundtrip(&[], 6));
+
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let noise: Vec<u8> = (0..200_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let coded = roundtrip(&noise, 6);
+        assert_eq!(STORED, block_type(&coded));
+        assert!(coded.len() < noise.len() + 50);
+    }
+
+    #[test]
+    fn empty_block() {
+        // an empty block after a single bit needs less padding than a stored block header
+        let mut encoded = Symbol {
+            bytes: Vec::new(),
+            bitpos: 0,
+            bytepos: 0,
+        };
+        encoded.append_bits_lsb_first(0, 1);
+        write_block(&mut encoded, &[], &[], true);
+        assert!(encoded.bits() > 1);
+    }
+
+    #[test]
+    fn levels() {
+        let zlib_size = include_bytes!("../testdata/deflate/dynamic.zz").len();
+        let sizes: Vec<usize> = [0, 1, 6, 9]
+            .into_iter()
+            .map(|level| roundtrip(TEXT, level).len())
+            .collect();
+        assert!(sizes.windows(2).all(|pair| pair[0] >= pair[1]));
+        // level 9 of zlib, compressing about as well shows the codes are optimal
+        assert!(sizes[3] < zlib_size * 21 / 20);
+    }
+
+    #[test]
+    fn framing() {
+        let mut coded = Vec::new();
+        hencode_gzip(&mut &TEXT[..], &mut coded, 9).expect("encoding failed");
+        let mut out = Vec::new();
+        GzDecoder::new(&coded[..])
+            .expect("invalid header")
+            .read_to_end(&mut out)
+            .expect("decoding failed");
+        assert_eq!(TEXT, out);
+
+        for level in [0, 4, 6, 9] {
+            let mut coded = Vec::new();
+            hencode_zlib(&mut &TEXT[..], &mut coded, level).expect("encoding failed");
+            let mut out = Vec::new();
+            ZlibDecoder::new(&coded[..])
+                .expect("invalid header")
+                .read_to_end(&mut out)
+                .expect("decoding failed");
+            assert_eq!(TEXT, out);
+        }
+    }
+
+    #[test]
+    fn code_length_runs() {
+        let mut lengths = vec![0; 20];
+        lengths.extend([5; 8]);
+        lengths.extend([0; 3]);
+        lengths.push(7);
+        assert_eq!(
+            vec![(18, 9), (5, 0), (16, 3), (5, 0), (17, 0), (7, 0)],
+            run_lengths(&lengths)
+        );
+        assert_eq!(vec![(0, 0), (0, 0), (3, 0)], run_lengths(&[0, 0, 3]));
+    }
+}
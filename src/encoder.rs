@@ -0,0 +1,101 @@
+use std::io::{self, prelude::*};
+
+use tempfile::SpooledTempFile;
+
+use crate::{hencode_context_seekable, hencode_seekable, Error};
+
+/// Data up to this size is buffered in memory, larger inputs go to a temporary file
+pub(crate) const SPOOL_MEMORY: usize = 16 * 1024 * 1024;
+
+/// Push based encoder wrapping a [`Write`].
+///
+/// All data written to the encoder is buffered (in memory up to a limit, in a temporary
+/// file beyond that) since the tree can only be built once all data is known.
+/// The tree header and the encoded payload are written to the wrapped writer by
+/// [`finish`]. Dropping the encoder without calling [`finish`] discards all data.
+///
+/// [`finish`]: Encoder::finish
+pub struct Encoder<W: Write> {
+    output: W,
+    spool: SpooledTempFile,
+    /// use one tree per preceding byte, see [`hencode_context_seekable`]
+    context: bool,
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(output: W) -> Self {
+        Encoder {
+            output,
+            spool: SpooledTempFile::new(SPOOL_MEMORY),
+            context: false,
+        }
+    }
+
+    /// Creates an encoder using order-1 context modeling, see [`hencode_context_seekable`]
+    pub fn with_context(output: W) -> Self {
+        Encoder {
+            context: true,
+            ..Encoder::new(output)
+        }
+    }
+
+    /// Encodes all data written so far into the wrapped writer
+    ///
+    /// # Returns
+    /// The wrapped writer
+    pub fn finish(mut self) -> Result<W, Error> {
+        self.spool.rewind()?;
+        if self.context {
+            hencode_context_seekable(&mut self.spool, &mut self.output)?;
+        } else {
+            hencode_seekable(&mut self.spool, &mut self.output)?;
+        }
+        Ok(self.output)
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.spool.write(buf)
+    }
+
+    /// Nothing can be written to the wrapped writer before [`Encoder::finish`] is called,
+    /// so this only flushes the internal buffer.
+    fn flush(&mut self) -> io::Result<()> {
+        self.spool.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+
+    use super::Encoder;
+    use crate::hdecode;
+
+    #[test]
+    fn roundtrip_copy() {
+        let raw = b"encoders can sit inside io::copy pipelines like any other writer";
+        let mut encoder = Encoder::new(Vec::new());
+        io::copy(&mut &raw[..], &mut encoder).expect("io err");
+        let coded = encoder.finish().expect("encoding failed");
+
+        let mut out = Vec::new();
+        hdecode(&coded[..], &mut out).expect("decoding failed");
+        assert_eq!(&raw[..], &out);
+    }
+
+    #[test]
+    fn roundtrip_chunked_writes() {
+        let raw = b"aaaaaaaabbbbccd and some more text written in small chunks";
+        let mut encoder = Encoder::new(Vec::new());
+        for chunk in raw.chunks(3) {
+            encoder.write_all(chunk).expect("io err");
+        }
+        let coded = encoder.finish().expect("encoding failed");
+
+        let mut out = Vec::new();
+        hdecode(&coded[..], &mut out).expect("decoding failed");
+        assert_eq!(&raw[..], &out);
+    }
+}
@@ -4,13 +4,28 @@ use std::{fmt::Display, io};
 pub enum Error {
     // tried to operate on more bits then were available
     NoBits,
+    // the input contained a byte that is not part of the supplied tree
+    NotInTree(u8),
+    // the data was encoded using a different model
+    ModelMismatch { expected: u64, found: u64 },
+    // the checksum of the decoded data does not match the stored one
+    ChecksumMismatch { expected: u32, found: u32 },
+    // the payload ended before the number of symbols stored in the header was decoded
+    Truncated { expected: u64, decoded: u64 },
+    // the payload continues after the number of symbols stored in the header
+    Overlong { expected: u64 },
     IoError(io::Error),
 }
 
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
-            Error::NoBits => None,
+            Error::NoBits
+            | Error::NotInTree(_)
+            | Error::ModelMismatch { .. }
+            | Error::ChecksumMismatch { .. }
+            | Error::Truncated { .. }
+            | Error::Overlong { .. } => None,
             Error::IoError(err) => Some(err),
         }
     }
@@ -28,6 +43,23 @@ impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::NoBits => writeln!(f, "tried to operate on more bits than were available"),
+            Error::NotInTree(byte) => writeln!(f, "byte {byte} cannot be encoded by the tree"),
+            Error::ModelMismatch { expected, found } => writeln!(
+                f,
+                "data was encoded using model {found:016x}, not with model {expected:016x}"
+            ),
+            Error::ChecksumMismatch { expected, found } => writeln!(
+                f,
+                "decoded data has checksum {found:08x} instead of {expected:08x}, the data is corrupted"
+            ),
+            Error::Truncated { expected, decoded } => writeln!(
+                f,
+                "payload ended after {decoded} of {expected} symbols, the data is truncated"
+            ),
+            Error::Overlong { expected } => writeln!(
+                f,
+                "payload continues after the last of {expected} symbols, the data is corrupted"
+            ),
             Error::IoError(err) => err.fmt(f),
         }
     }
@@ -35,6 +67,11 @@ impl Display for Error {
 
 impl From<io::Error> for Error {
     fn from(value: io::Error) -> Self {
+        // unwrap errors that were converted into io::Error before
+        if value.get_ref().is_some_and(|err| err.is::<Error>()) {
+            let inner = value.into_inner().expect("checked above");
+            return *inner.downcast::<Error>().expect("checked above");
+        }
         Error::IoError(value)
     }
 }
@@ -43,6 +80,11 @@ impl From<Error> for io::Error {
     fn from(val: Error) -> Self {
         match val {
             Error::NoBits => io::Error::other(val),
+            Error::NotInTree(_) => io::Error::new(io::ErrorKind::InvalidInput, val),
+            Error::ModelMismatch { .. }
+            | Error::ChecksumMismatch { .. }
+            | Error::Overlong { .. } => io::Error::new(io::ErrorKind::InvalidData, val),
+            Error::Truncated { .. } => io::Error::new(io::ErrorKind::UnexpectedEof, val),
             Error::IoError(err) => err,
         }
     }
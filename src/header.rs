@@ -0,0 +1,86 @@
+use std::io::{self, prelude::*};
+
+pub(crate) const HEADER_START: &[u8; 30] = b"----- rxh tree start V9 -----\n";
+pub(crate) const HEADER_END: &[u8; 29] = b"\n----- rxh tree end V9 -----\n";
+const INVALID_VERSION: &str = r#"file does not contain a valid rxh tree start signature.
+If the file contains a valid signature from a prior version you may find a version of this program thats compatible with this file here: https://github.com/devensiv/huffman-coding"#;
+
+/// Layout of the encoded data, stored right after [`HEADER_START`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Mode {
+    /// code lengths of a single tree, followed by the payload
+    Static = 0,
+    /// a sequence of blocks, each with its own tree or reusing the previous one
+    Blocks = 1,
+    /// a single adaptive huffman coded stream, no tree is stored
+    Adaptive = 2,
+    /// width of the keys and the code lengths of a single tree over them, followed by the payload
+    Symbols = 3,
+    /// a fallback tree and one tree per preceding byte, followed by the payload
+    Context = 4,
+    /// the applied transforms, followed by a sequence of transformed blocks
+    Transform = 5,
+    /// a sequence of LZ77 blocks with separate trees for literals and distances
+    Lz77 = 6,
+    /// code lengths of a single tree and a seek index, followed by the payload
+    Indexed = 7,
+    /// the chunk size and a shared tree, followed by a sequence of independent chunks
+    Chunked = 8,
+    /// code lengths of a single tree and the number of bytes, followed by blocks of 4 streams
+    /// that start with their sizes
+    Interleaved = 9,
+}
+
+impl TryFrom<u8> for Mode {
+    type Error = io::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Mode::Static),
+            1 => Ok(Mode::Blocks),
+            2 => Ok(Mode::Adaptive),
+            3 => Ok(Mode::Symbols),
+            4 => Ok(Mode::Context),
+            5 => Ok(Mode::Transform),
+            6 => Ok(Mode::Lz77),
+            7 => Ok(Mode::Indexed),
+            8 => Ok(Mode::Chunked),
+            9 => Ok(Mode::Interleaved),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unknown mode")),
+        }
+    }
+}
+
+/// Checks that `input` starts with [`HEADER_START`]
+pub(crate) fn read_start(input: &mut impl Read) -> Result<(), io::Error> {
+    let mut buffer = [0u8; HEADER_START.len()];
+    input.read_exact(&mut buffer)?;
+    if &buffer != HEADER_START {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, INVALID_VERSION));
+    }
+    Ok(())
+}
+
+/// Checks that `input` starts with [`HEADER_END`]
+pub(crate) fn read_end(input: &mut impl Read) -> Result<(), io::Error> {
+    let mut buffer = [0u8; HEADER_END.len()];
+    input.read_exact(&mut buffer)?;
+    if &buffer != HEADER_END {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, INVALID_VERSION));
+    }
+    Ok(())
+}
+
+/// Writes [`HEADER_START`] followed by `mode`
+pub(crate) fn write_mode(output: &mut impl Write, mode: Mode) -> Result<(), io::Error> {
+    output.write_all(HEADER_START)?;
+    output.write_all(&[mode as u8])
+}
+
+/// Reads the [`HEADER_START`] and the mode following it
+pub(crate) fn read_mode(input: &mut impl Read) -> Result<Mode, io::Error> {
+    read_start(input)?;
+    let mut mode = [0u8];
+    input.read_exact(&mut mode)?;
+    Mode::try_from(mode[0])
+}
@@ -0,0 +1,265 @@
+use std::io::{self, prelude::*, BufReader, SeekFrom};
+
+use crate::bitutils::{read_varint, write_varint};
+use crate::checksum::write_trailer;
+use crate::header::{self, Mode, HEADER_END};
+use crate::tree::Tree;
+use crate::{count, encode_payload, leaves, payload_bits, Error};
+
+/// Default number of bytes between two checkpoints of the seek index
+pub const DEFAULT_INDEX_INTERVAL: usize = 1024 * 1024;
+
+/// Encodes `input` starting at its current position into `output` like
+/// [`hencode_seekable`](crate::hencode_seekable), storing a seek index in the header.
+///
+/// The index records the bit offset into the payload every `interval` bytes of the
+/// original data, [`Decoder::seekable`](crate::Decoder::seekable) uses it to jump to the
+/// checkpoint before any position instead of decoding everything before it.
+/// Each checkpoint costs a few bytes, so larger intervals trade seek time for size.
+///
+/// # Panics
+/// This function panics if `interval` is 0
+pub fn hencode_indexed(
+    input: &mut (impl Read + Seek),
+    output: &mut impl Write,
+    interval: usize,
+) -> Result<(), Error> {
+    assert!(interval > 0, "checkpoints must be at least one byte apart");
+    let (counts, crc) = count(input)?;
+    let tree = Tree::mktree(leaves(&counts));
+    let mut lengths = tree.code_lengths();
+    lengths.resize(256, 0);
+    let index = SeekIndex::build(&lengths, input, interval as u64)?;
+
+    header::write_mode(output, Mode::Indexed)?;
+    tree.store_lengths(output)?;
+    let bits = payload_bits(&lengths, &counts).expect("tree contains all bytes");
+    write_varint(output, counts.iter().sum::<usize>() as u64)?;
+    write_varint(output, bits.div_ceil(8) as u64)?;
+    index.store(output)?;
+    output.write_all(HEADER_END)?;
+    encode_payload(&tree, input, output)?;
+    write_trailer(output, &crc)?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Bit offsets into a payload at fixed intervals of the decoded data
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct SeekIndex {
+    /// number of decoded bytes between two checkpoints
+    interval: u64,
+    /// bit offset of every multiple of `interval` below the decoded length, starting at 0
+    offsets: Vec<u64>,
+}
+
+impl SeekIndex {
+    /// Index with only the start of the payload, seeking has to decode from there
+    pub(crate) fn start_only() -> Self {
+        SeekIndex {
+            interval: u64::MAX,
+            offsets: vec![0],
+        }
+    }
+
+    /// Computes the index of `input` encoded using the code `lengths` of every byte,
+    /// seeking back afterwards
+    fn build(lengths: &[u8], input: &mut (impl Read + Seek), interval: u64) -> Result<Self, Error> {
+        let start = input.stream_position()?;
+        let mut offsets = Vec::new();
+        let mut position = 0u64;
+        let mut bits = 0u64;
+        let mut reader = BufReader::new(&mut *input);
+        loop {
+            let buf = reader.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            for &byte in buf {
+                if position.is_multiple_of(interval) {
+                    offsets.push(bits);
+                }
+                bits += lengths[byte as usize] as u64;
+                position += 1;
+            }
+            let len = buf.len();
+            reader.consume(len);
+        }
+        drop(reader);
+        input.seek(SeekFrom::Start(start))?;
+        Ok(SeekIndex { interval, offsets })
+    }
+
+    /// Writes the interval and the distances between the checkpoints as varints,
+    /// the first checkpoint is always at 0 and not stored
+    fn store(&self, output: &mut impl Write) -> io::Result<()> {
+        write_varint(output, self.interval)?;
+        let distances: Vec<u64> = self
+            .offsets
+            .windows(2)
+            .map(|pair| pair[1] - pair[0])
+            .collect();
+        write_varint(output, distances.len() as u64)?;
+        for distance in distances {
+            write_varint(output, distance)?;
+        }
+        Ok(())
+    }
+
+    /// Reads an index written by [`SeekIndex::store`] for a payload of `len` bytes that
+    /// takes `size` bytes
+    ///
+    /// # Errors
+    /// This method returns an error if the index does not have one checkpoint per
+    /// `interval` bytes or if the offsets do not increase within the payload
+    pub(crate) fn load(input: &mut impl Read, len: u64, size: u64) -> Result<Self, Error> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid seek index");
+        let interval = read_varint(input)?;
+        if interval == 0 {
+            return Err(invalid().into());
+        }
+        if read_varint(input)? != len.div_ceil(interval).saturating_sub(1) {
+            return Err(invalid().into());
+        }
+        // the stored count is not trusted for allocations
+        let mut offsets: Vec<u64> = vec![0];
+        while (offsets.len() as u64) < len.div_ceil(interval) {
+            let last = offsets.last().copied().unwrap_or_default();
+            let distance = read_varint(input)?;
+            // every byte takes at least one bit
+            let offset = last
+                .checked_add(distance)
+                .filter(|&offset| distance >= interval && offset < size.saturating_mul(8))
+                .ok_or_else(invalid)?;
+            offsets.push(offset);
+        }
+        Ok(SeekIndex { interval, offsets })
+    }
+
+    /// Returns the position and bit offset of the last checkpoint at or before `position`
+    pub(crate) fn checkpoint(&self, position: u64) -> (u64, u64) {
+        let checkpoint = ((position / self.interval) as usize).min(self.offsets.len() - 1);
+        (checkpoint as u64 * self.interval, self.offsets[checkpoint])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{hdecode, hencode_seekable, Decoder};
+
+    const SOURCE: &str = include_str!("decoder.rs");
+
+    fn encode(interval: usize) -> Vec<u8> {
+        let mut coded = Vec::new();
+        hencode_indexed(&mut Cursor::new(SOURCE), &mut coded, interval).expect("encoding failed");
+        coded
+    }
+
+    #[test]
+    fn random_access() {
+        let coded = encode(100);
+        let mut out = Vec::new();
+        hdecode(&coded[..], &mut out).expect("decoding failed");
+        assert_eq!(SOURCE.as_bytes(), &out);
+
+        let mut decoder = Decoder::seekable(Cursor::new(&coded)).expect("invalid header");
+        let mut buf = [0u8; 50];
+        let len = SOURCE.len() as u64;
+        for position in [1234, 17, 0, 100, 99, 4000, len - 50, 3999] {
+            assert_eq!(
+                position,
+                decoder
+                    .seek(SeekFrom::Start(position))
+                    .expect("seek failed")
+            );
+            decoder.read_exact(&mut buf).expect("decoding failed");
+            let position = position as usize;
+            assert_eq!(&SOURCE.as_bytes()[position..position + 50], &buf);
+        }
+        assert_eq!(
+            len - 10,
+            decoder.seek(SeekFrom::End(-10)).expect("seek failed")
+        );
+        assert_eq!(
+            len - 5,
+            decoder.seek(SeekFrom::Current(5)).expect("seek failed")
+        );
+        assert!(decoder.seek(SeekFrom::Current(6)).is_err());
+
+        // the checksum is verified again when decoding everything from the start
+        decoder.rewind().expect("seek failed");
+        let mut out = Vec::new();
+        decoder.read_to_end(&mut out).expect("decoding failed");
+        assert_eq!(SOURCE.as_bytes(), &out);
+    }
+
+    #[test]
+    fn without_index() {
+        let mut coded = Vec::new();
+        hencode_seekable(&mut Cursor::new(SOURCE), &mut coded).expect("encoding failed");
+        let mut decoder = Decoder::seekable(Cursor::new(&coded)).expect("invalid header");
+        let mut buf = [0u8; 20];
+        for position in [300, 10] {
+            decoder
+                .seek(SeekFrom::Start(position))
+                .expect("seek failed");
+            decoder.read_exact(&mut buf).expect("decoding failed");
+            let position = position as usize;
+            assert_eq!(&SOURCE.as_bytes()[position..position + 20], &buf);
+        }
+
+        // the decoder does not know where the payload starts
+        let mut decoder = Decoder::new(Cursor::new(&coded)).expect("invalid header");
+        assert!(decoder.seek(SeekFrom::Start(0)).is_err());
+        let coded = encode(100);
+        let mut decoder = Decoder::new(&coded[..]).expect("invalid header");
+        let mut out = Vec::new();
+        decoder.read_to_end(&mut out).expect("decoding failed");
+        assert_eq!(SOURCE.as_bytes(), &out);
+    }
+
+    #[test]
+    fn index_size() {
+        let sparse = encode(DEFAULT_INDEX_INTERVAL);
+        let dense = encode(100);
+        let checkpoints = SOURCE.len().div_ceil(100) - 1;
+        // distances of 100 bytes fit into two bytes
+        assert!(dense.len() - sparse.len() <= 2 * checkpoints + 2);
+
+        let mut empty = Vec::new();
+        hencode_indexed(&mut Cursor::new([]), &mut empty, 10).expect("encoding failed");
+        let mut decoder = Decoder::seekable(Cursor::new(&empty)).expect("invalid header");
+        assert_eq!(0, decoder.seek(SeekFrom::End(0)).expect("seek failed"));
+        assert_eq!(0, decoder.read(&mut [0u8; 4]).expect("decoding failed"));
+    }
+
+    #[test]
+    fn corrupted_index() {
+        let index = SeekIndex {
+            interval: 10,
+            offsets: vec![0, 30, 45],
+        };
+        let mut stored = Vec::new();
+        index.store(&mut stored).expect("io err");
+        let load = |len, size| SeekIndex::load(&mut &stored[..], len, size);
+        assert_eq!(index, load(25, 8).expect("valid index"));
+        // the number of checkpoints does not match the length
+        assert!(load(31, 8).is_err());
+        assert!(load(20, 8).is_err());
+        // the checkpoints lie past the end of the payload
+        assert!(load(25, 5).is_err());
+
+        // checkpoints closer than one bit per byte
+        let mut stored = Vec::new();
+        SeekIndex {
+            interval: 10,
+            offsets: vec![0, 30, 35],
+        }
+        .store(&mut stored)
+        .expect("io err");
+        assert!(SeekIndex::load(&mut &stored[..], 25, 8).is_err());
+    }
+}
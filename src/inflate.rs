@@ -0,0 +1,541 @@
+use std::io::{self, prelude::*};
+
+use crate::checksum::{Adler32, Crc32};
+use crate::lz77::{
+    read_bits, DISTANCE_BASE, DISTANCE_EXTRA, END_OF_BLOCK, LENGTH_BASE, LENGTH_EXTRA, WINDOW_SIZE,
+};
+use crate::table::Table;
+use crate::tree::Key;
+use crate::window::BitWindow;
+use crate::Error;
+
+/// Order in which the code lengths of the code length alphabet are stored
+pub(crate) const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+/// Size of the literal/length alphabet including the two unused symbols
+pub(crate) const LITERALS: usize = 288;
+/// Size of the distance alphabet including the two unused symbols
+pub(crate) const DISTANCES: usize = 32;
+pub(crate) const FIRST_LENGTH: u16 = 257;
+
+pub(crate) const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// Compression method of RFC 1950 and RFC 1952
+pub(crate) const DEFLATE: u8 = 8;
+const FHCRC: u8 = 1 << 1;
+const FEXTRA: u8 = 1 << 2;
+const FNAME: u8 = 1 << 3;
+const FCOMMENT: u8 = 1 << 4;
+const FDICT: u8 = 1 << 5;
+
+enum State {
+    /// the next bits start a block header
+    Header,
+    /// number of bytes left in a stored block
+    Stored(usize),
+    Huffman {
+        literals: Table<u16>,
+        distances: Table<u8>,
+    },
+    /// the last block was decoded
+    Done,
+}
+
+/// Pull based decoder for raw DEFLATE data (RFC 1951), without any framing.
+///
+/// Use [`GzDecoder`] or [`ZlibDecoder`] for data framed by gzip or zlib.
+pub struct Inflater<R: BufRead> {
+    window: BitWindow<R>,
+    /// decoded data, starting with the history kept for matches
+    buffer: Vec<u8>,
+    /// number of bytes in `buffer` that were returned already
+    position: usize,
+    state: State,
+    /// the block that is decoded has the final bit set
+    last: bool,
+}
+
+impl<R: BufRead> Inflater<R> {
+    pub fn new(input: R) -> Self {
+        Inflater::with_window(BitWindow::lsb_first(input))
+    }
+
+    fn with_window(window: BitWindow<R>) -> Self {
+        Inflater {
+            window,
+            buffer: Vec::new(),
+            position: 0,
+            state: State::Header,
+            last: false,
+        }
+    }
+
+    /// Prepares decoding the next stream, dropping the history of the last one
+    fn restart(&mut self) {
+        self.buffer.clear();
+        self.position = 0;
+        self.state = State::Header;
+        self.last = false;
+    }
+
+    /// Decodes up to 32 KiB into `buffer`, keeping 32 KiB of history in front of it
+    fn fill(&mut self) -> Result<(), Error> {
+        if self.buffer.len() > 2 * WINDOW_SIZE {
+            let drop = self.buffer.len() - WINDOW_SIZE;
+            self.buffer.drain(..drop);
+            self.position -= drop;
+        }
+        let target = self.buffer.len() + WINDOW_SIZE;
+        let Inflater {
+            window,
+            buffer,
+            state,
+            last,
+            ..
+        } = self;
+        while buffer.len() < target {
+            match state {
+                State::Done => break,
+                State::Header if *last => *state = State::Done,
+                State::Header => {
+                    *last = read_lsb(window, 1)? == 1;
+                    *state = read_block_header(window)?;
+                }
+                State::Stored(0) => *state = State::Header,
+                State::Stored(remaining) => {
+                    let amt = (*remaining).min(target - buffer.len());
+                    for _ in 0..amt {
+                        buffer.push(read_byte(window)?);
+                    }
+                    *remaining -= amt;
+                }
+                State::Huffman {
+                    literals,
+                    distances,
+                } => match literals.decode(window)? {
+                    symbol @ 0..=255 => buffer.push(symbol as u8),
+                    END_OF_BLOCK => *state = State::Header,
+                    symbol => copy_match(window, buffer, symbol, distances)?,
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for Inflater<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.position == self.buffer.len() {
+            if let State::Done = self.state {
+                return Ok(0);
+            }
+            self.fill()?;
+        }
+        let read = (self.buffer.len() - self.position).min(buf.len());
+        buf[..read].copy_from_slice(&self.buffer[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+/// Pull based decoder for gzip files (RFC 1952).
+///
+/// Files consisting of multiple members are decoded as the concatenation of the members.
+/// The CRC32 and the size in the trailer of every member are verified.
+pub struct GzDecoder<R: BufRead> {
+    inflater: Inflater<R>,
+    crc: Crc32,
+    /// size of the member modulo 2^32
+    size: u32,
+    done: bool,
+}
+
+impl<R: BufRead> GzDecoder<R> {
+    /// Reads the header of the first member from `input`
+    ///
+    /// # Errors
+    /// This method returns an error if `input` does not start with a valid gzip header
+    pub fn new(input: R) -> Result<Self, Error> {
+        let mut window = BitWindow::lsb_first(input);
+        read_gzip_header(&mut window)?;
+        Ok(GzDecoder {
+            inflater: Inflater::with_window(window),
+            crc: Crc32::new(),
+            size: 0,
+            done: false,
+        })
+    }
+
+    /// Verifies the trailer of the current member and starts the next one if there is one
+    fn next_member(&mut self) -> Result<(), Error> {
+        let window = &mut self.inflater.window;
+        align(window)?;
+        self.crc.verify(read_le(window, 4)?)?;
+        if read_le(window, 4)? != self.size {
+            return Err(invalid_data("size of the decoded data does not match").into());
+        }
+        // the window is byte aligned, so it only runs empty at EOF
+        if window.initialized() == 0 {
+            self.done = true;
+            return Ok(());
+        }
+        read_gzip_header(window)?;
+        self.inflater.restart();
+        self.crc = Crc32::new();
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for GzDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while !self.done && !buf.is_empty() {
+            let read = self.inflater.read(buf)?;
+            if read > 0 {
+                self.crc.update(&buf[..read]);
+                self.size = self.size.wrapping_add(read as u32);
+                return Ok(read);
+            }
+            self.next_member()?;
+        }
+        Ok(0)
+    }
+}
+
+/// Reads a gzip member header, verifying its CRC16 if present
+fn read_gzip_header<R: BufRead>(window: &mut BitWindow<R>) -> Result<(), Error> {
+    let mut crc = Crc32::new();
+    let mut byte = |window: &mut BitWindow<R>| -> Result<u8, Error> {
+        let byte = read_byte(window)?;
+        crc.update(&[byte]);
+        Ok(byte)
+    };
+    if [byte(window)?, byte(window)?] != GZIP_MAGIC {
+        return Err(invalid_data("not a gzip file").into());
+    }
+    if byte(window)? != DEFLATE {
+        return Err(invalid_data("unsupported compression method").into());
+    }
+    let flags = byte(window)?;
+    if flags & 0xe0 != 0 {
+        return Err(invalid_data("reserved gzip flags are set").into());
+    }
+    // modification time, extra flags and operating system
+    for _ in 0..6 {
+        byte(window)?;
+    }
+    if flags & FEXTRA != 0 {
+        let len = byte(window)? as usize | (byte(window)? as usize) << 8;
+        for _ in 0..len {
+            byte(window)?;
+        }
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            // zero terminated string
+            while byte(window)? != 0 {}
+        }
+    }
+    if flags & FHCRC != 0 {
+        let sum = crc.sum();
+        if read_le(window, 2)? != sum & 0xffff {
+            return Err(invalid_data("crc16 of the gzip header does not match").into());
+        }
+    }
+    Ok(())
+}
+
+/// Pull based decoder for zlib streams (RFC 1950).
+///
+/// The Adler-32 in the trailer is verified, data following the stream is ignored.
+/// Streams that need a preset dictionary are not supported.
+pub struct ZlibDecoder<R: BufRead> {
+    inflater: Inflater<R>,
+    adler: Adler32,
+    done: bool,
+}
+
+impl<R: BufRead> ZlibDecoder<R> {
+    /// Reads the zlib header from `input`
+    ///
+    /// # Errors
+    /// This method returns an error if `input` does not start with a valid zlib header
+    pub fn new(input: R) -> Result<Self, Error> {
+        let mut window = BitWindow::lsb_first(input);
+        let method = read_byte(&mut window)?;
+        let flags = read_byte(&mut window)?;
+        if !((method as u16) << 8 | flags as u16).is_multiple_of(31) {
+            return Err(invalid_data("zlib header check failed").into());
+        }
+        // the upper bits are the log2 of the window size minus 8
+        if method & 0xf != DEFLATE || method >> 4 > 7 {
+            return Err(invalid_data("unsupported compression method").into());
+        }
+        if flags & FDICT != 0 {
+            return Err(invalid_data("preset dictionaries are not supported").into());
+        }
+        Ok(ZlibDecoder {
+            inflater: Inflater::with_window(window),
+            adler: Adler32::new(),
+            done: false,
+        })
+    }
+}
+
+impl<R: BufRead> Read for ZlibDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        let read = self.inflater.read(buf)?;
+        if read > 0 {
+            self.adler.update(&buf[..read]);
+            return Ok(read);
+        }
+        let window = &mut self.inflater.window;
+        align(window)?;
+        // the only big endian number of the format
+        let sum = (0..4).try_fold(0, |sum, _| {
+            Ok::<_, Error>(sum << 8 | read_byte(window)? as u32)
+        })?;
+        if sum != self.adler.sum() {
+            return Err(Error::ChecksumMismatch {
+                expected: sum,
+                found: self.adler.sum(),
+            }
+            .into());
+        }
+        self.done = true;
+        Ok(0)
+    }
+}
+
+/// Reads the type of a block and everything in front of its data
+fn read_block_header<R: BufRead>(window: &mut BitWindow<R>) -> Result<State, Error> {
+    match read_lsb(window, 2)? {
+        0 => {
+            align(window)?;
+            let len = read_le(window, 2)?;
+            if len ^ read_le(window, 2)? != 0xffff {
+                return Err(invalid_data("stored block length is corrupted").into());
+            }
+            Ok(State::Stored(len as usize))
+        }
+        1 => Ok(fixed_tables()),
+        2 => dynamic_tables(window),
+        _ => Err(invalid_data("invalid block type").into()),
+    }
+}
+
+/// Reads the distance of the match starting with the length `symbol` and appends it
+fn copy_match<R: BufRead>(
+    window: &mut BitWindow<R>,
+    buffer: &mut Vec<u8>,
+    symbol: u16,
+    distances: &Table<u8>,
+) -> Result<(), Error> {
+    let code = (symbol - FIRST_LENGTH) as usize;
+    if code >= LENGTH_BASE.len() {
+        return Err(invalid_data("invalid length code").into());
+    }
+    let length = LENGTH_BASE[code] + read_lsb(window, LENGTH_EXTRA[code])?;
+    let code = distances.decode(window)? as usize;
+    if code >= DISTANCE_BASE.len() {
+        return Err(invalid_data("invalid distance code").into());
+    }
+    let distance = (DISTANCE_BASE[code] + read_lsb(window, DISTANCE_EXTRA[code])?) as usize;
+    if distance > buffer.len() {
+        return Err(invalid_data("distance reaches before the data").into());
+    }
+    // matches may overlap the bytes they produce
+    let start = buffer.len() - distance;
+    for pos in start..start + length as usize {
+        buffer.push(buffer[pos]);
+    }
+    Ok(())
+}
+
+/// Code lengths of the fixed literal/length code, all fixed distance codes are 5 bits long
+pub(crate) fn fixed_lengths() -> [u8; LITERALS] {
+    let mut lengths = [8u8; LITERALS];
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths
+}
+
+/// Builds the tables of a block using the fixed codes
+fn fixed_tables() -> State {
+    State::Huffman {
+        literals: Table::from_lengths(&fixed_lengths()).expect("fixed code is complete"),
+        distances: Table::from_lengths(&[5; DISTANCES]).expect("fixed code is complete"),
+    }
+}
+
+/// Reads the code lengths of a block using dynamic codes and builds its tables
+fn dynamic_tables<R: BufRead>(window: &mut BitWindow<R>) -> Result<State, Error> {
+    let literal_count = read_lsb(window, 5)? as usize + 257;
+    let distance_count = read_lsb(window, 5)? as usize + 1;
+    let length_count = read_lsb(window, 4)? as usize + 4;
+    if literal_count > 286 || distance_count > 30 {
+        return Err(invalid_data("too many codes").into());
+    }
+    let mut code_lengths = [0u8; 19];
+    for &key in &CODE_LENGTH_ORDER[..length_count] {
+        code_lengths[key] = read_lsb(window, 3)? as u8;
+    }
+    let code_lengths: Table = Table::from_lengths(&code_lengths)
+        .ok_or_else(|| invalid_data("code length code cannot be decoded"))?;
+
+    // literal and distance lengths are one sequence, repeats may cross from one to the other
+    let count = literal_count + distance_count;
+    let mut lengths = Vec::with_capacity(count);
+    while lengths.len() < count {
+        let (len, repeat) = match code_lengths.decode(window)? {
+            len @ 0..=15 => (len, 1),
+            16 => {
+                let &previous = lengths
+                    .last()
+                    .ok_or_else(|| invalid_data("repeated length without previous length"))?;
+                (previous, 3 + read_lsb(window, 2)?)
+            }
+            17 => (0, 3 + read_lsb(window, 3)?),
+            _ => (0, 11 + read_lsb(window, 7)?),
+        };
+        if lengths.len() + repeat as usize > count {
+            return Err(invalid_data("repeated lengths exceed the number of codes").into());
+        }
+        lengths.extend((0..repeat).map(|_| len));
+    }
+
+    let (literals, distances) = lengths.split_at(literal_count);
+    if literals[END_OF_BLOCK as usize] == 0 {
+        return Err(invalid_data("code is missing the end of block").into());
+    }
+    Ok(State::Huffman {
+        literals: load_table(literals, LITERALS)?,
+        distances: load_table(distances, DISTANCES)?,
+    })
+}
+
+/// Builds the table of a code with `size` symbols from the stored prefix of its `lengths`
+///
+/// Codes with less than two symbols are completed by the unused symbols at the end of the
+/// alphabet, decoding those is an error.
+fn load_table<K: Key>(lengths: &[u8], size: usize) -> Result<Table<K>, Error> {
+    let mut full = vec![0u8; size];
+    full[..lengths.len()].copy_from_slice(lengths);
+    match full.iter().filter(|&&len| len > 0).count() {
+        0 => full[size - 2..].fill(1),
+        1 if full.contains(&1) => {
+            let unused = if full[size - 1] == 0 {
+                size - 1
+            } else {
+                size - 2
+            };
+            full[unused] = 1;
+        }
+        _ => (),
+    }
+    Ok(Table::from_lengths(&full).ok_or_else(|| invalid_data("code cannot be decoded"))?)
+}
+
+/// Reads a `bits` wide number that is packed least significant bit first
+fn read_lsb<R: BufRead>(window: &mut BitWindow<R>, bits: u8) -> Result<u16, Error> {
+    if bits == 0 {
+        return Ok(0);
+    }
+    Ok(read_bits(window, bits)?.reverse_bits() >> (16 - bits))
+}
+
+fn read_byte<R: BufRead>(window: &mut BitWindow<R>) -> Result<u8, Error> {
+    Ok(read_lsb(window, 8)? as u8)
+}
+
+/// Reads a little endian number of `bytes` bytes
+fn read_le<R: BufRead>(window: &mut BitWindow<R>, bytes: usize) -> Result<u32, Error> {
+    (0..bytes).try_fold(0, |value, byte| {
+        Ok(value | (read_byte(window)? as u32) << (8 * byte))
+    })
+}
+
+/// Skips the bits up to the next byte boundary
+fn align<R: BufRead>(window: &mut BitWindow<R>) -> Result<(), Error> {
+    window.consume(window.initialized() % 8)?;
+    Ok(())
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &[u8] = include_bytes!("../testdata/deflate/text.txt");
+    const REPEAT: &[u8] = include_bytes!("../testdata/deflate/repeat.bin");
+
+    fn read_all(mut decoder: impl Read) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        decoder.read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    fn zlib(data: &[u8]) -> io::Result<Vec<u8>> {
+        read_all(ZlibDecoder::new(data)?)
+    }
+
+    fn gzip(data: &[u8]) -> io::Result<Vec<u8>> {
+        read_all(GzDecoder::new(data)?)
+    }
+
+    #[test]
+    fn block_types() {
+        let stored = include_bytes!("../testdata/deflate/stored.zz");
+        let fixed = include_bytes!("../testdata/deflate/fixed.zz");
+        let dynamic = include_bytes!("../testdata/deflate/dynamic.zz");
+        for data in [&stored[..], &fixed[..], &dynamic[..]] {
+            assert_eq!(TEXT, zlib(data).expect("decoding failed"));
+        }
+        let repeat = include_bytes!("../testdata/deflate/repeat.zz");
+        assert_eq!(REPEAT, zlib(repeat).expect("decoding failed"));
+
+        let raw = include_bytes!("../testdata/deflate/raw.deflate");
+        assert_eq!(
+            TEXT,
+            read_all(Inflater::new(&raw[..])).expect("decoding failed")
+        );
+    }
+
+    #[test]
+    fn gzip_files() {
+        let named = include_bytes!("../testdata/deflate/text.txt.gz");
+        let cli = include_bytes!("../testdata/deflate/gzip_cli.gz");
+        let members = include_bytes!("../testdata/deflate/members.gz");
+        for data in [&named[..], &cli[..], &members[..]] {
+            assert_eq!(TEXT, gzip(data).expect("decoding failed"));
+        }
+        let empty = include_bytes!("../testdata/deflate/empty.gz");
+        assert!(gzip(empty).expect("decoding failed").is_empty());
+    }
+
+    #[test]
+    fn corrupted() {
+        let bad_crc = include_bytes!("../testdata/deflate/bad_crc.gz");
+        let err = gzip(bad_crc).expect_err("crc32 does not match");
+        assert!(matches!(Error::from(err), Error::ChecksumMismatch { .. }));
+        let bad_adler = include_bytes!("../testdata/deflate/bad_adler.zz");
+        let err = zlib(bad_adler).expect_err("adler32 does not match");
+        assert!(matches!(Error::from(err), Error::ChecksumMismatch { .. }));
+
+        let dynamic = include_bytes!("../testdata/deflate/dynamic.zz");
+        assert!(zlib(&dynamic[..dynamic.len() / 2]).is_err());
+        assert!(zlib(&dynamic[2..]).is_err());
+        assert!(gzip(dynamic).is_err());
+        // block type 3 is reserved
+        assert!(read_all(Inflater::new(&[0b111][..])).is_err());
+    }
+}
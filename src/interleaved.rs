@@ -0,0 +1,360 @@
+use std::io::{self, prelude::*, BufReader, Cursor};
+use std::mem;
+
+use crate::bitutils::{read_varint, write_varint, Symbol};
+use crate::checksum::{read_trailer, write_trailer, Crc32};
+use crate::header::{self, Mode, HEADER_END};
+use crate::table::Table;
+use crate::tree::Tree;
+use crate::window::BitWindow;
+use crate::{count, leaves, Error};
+
+/// Number of bitstreams the payload is split into
+const STREAMS: usize = 4;
+
+/// Number of input bytes in every block but the last, a multiple of [`STREAMS`]
+const BLOCK_SIZE: usize = 128 * 1024;
+
+/// Encodes `input` starting at its current position into `output`, splitting the payload
+/// into 4 interleaved bitstreams.
+///
+/// The input is split into blocks of 128 KiB, byte `i` of a block is encoded into stream
+/// `i % 4` using a single tree. Every block starts with the sizes of its streams, so
+/// decoding only has to keep one block in memory. Decoding the streams alongside each
+/// other removes the dependency of every lookup on the previous one, so the CPU can
+/// overlap them, at the cost of a few bytes per block.
+///
+/// Like [`hencode_seekable`](crate::hencode_seekable) the input is counted first, then
+/// all streams are encoded in a second pass over `input`.
+pub fn hencode_interleaved(
+    input: &mut (impl Read + Seek),
+    output: &mut impl Write,
+) -> Result<(), Error> {
+    let (counts, crc) = count(input)?;
+    let tree = Tree::mktree(leaves(&counts));
+
+    header::write_mode(output, Mode::Interleaved)?;
+    tree.store_lengths(output)?;
+    write_varint(output, counts.iter().sum::<usize>() as u64)?;
+    output.write_all(HEADER_END)?;
+
+    let map = tree.make_conversion_map();
+    let mut streams: [Symbol; STREAMS] = std::array::from_fn(|_| Symbol {
+        bytes: Vec::new(),
+        bitpos: 0,
+        bytepos: 0,
+    });
+    let mut block = Vec::with_capacity(BLOCK_SIZE);
+    let mut reader = BufReader::new(input);
+    loop {
+        block.clear();
+        (&mut reader)
+            .take(BLOCK_SIZE as u64)
+            .read_to_end(&mut block)?;
+        if block.is_empty() {
+            break;
+        }
+        for round in block.chunks(STREAMS) {
+            for (stream, byte) in streams.iter_mut().zip(round) {
+                stream.append_sym(&map[byte]);
+            }
+        }
+        for stream in streams.iter() {
+            write_varint(output, stream.bytes.len() as u64)?;
+        }
+        for stream in streams.iter_mut() {
+            output.write_all(&stream.bytes)?;
+            stream.bytes.clear();
+            stream.bitpos = 0;
+            stream.bytepos = 0;
+        }
+    }
+    write_trailer(output, &crc)?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Pull based decoder for data encoded by [`hencode_interleaved`].
+///
+/// The streams of one block are read into memory at a time, every iteration then decodes
+/// one byte of each stream.
+pub struct InterleavedDecoder<R: BufRead> {
+    input: R,
+    table: Table,
+    /// length of the longest code, bounds the size of the streams
+    max_len: u64,
+    windows: [BitWindow<Cursor<Vec<u8>>>; STREAMS],
+    /// number of bytes in the payload
+    len: u64,
+    decoded: u64,
+    /// number of bytes in the current block
+    block_len: u64,
+    /// number of decoded bytes at the end of the current block
+    block_end: u64,
+    done: bool,
+    crc: Crc32,
+}
+
+impl<R: BufRead> InterleavedDecoder<R> {
+    /// Reads the header and the first block from `input` and prepares decoding them
+    ///
+    /// # Errors
+    /// This method returns an error if `input` does not start with a valid interleaved
+    /// mode header, or if it ends before the first block
+    pub fn new(mut input: R) -> Result<Self, Error> {
+        if header::read_mode(&mut input)? != Mode::Interleaved {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file does not use interleaved streams, use hdecode instead",
+            )
+            .into());
+        }
+        InterleavedDecoder::after_mode(input)
+    }
+
+    /// Prepares decoding interleaved mode data from `input` that is positioned after the mode
+    pub(crate) fn after_mode(mut input: R) -> Result<Self, Error> {
+        let lengths = Tree::<u8>::load_lengths(&mut input)?;
+        let len = read_varint(&mut input)?;
+        header::read_end(&mut input)?;
+        let table = Table::from_lengths(&lengths)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "code cannot be decoded"))?;
+
+        let mut decoder = InterleavedDecoder {
+            input,
+            table,
+            max_len: lengths.iter().copied().max().unwrap_or(0).into(),
+            windows: std::array::from_fn(|_| BitWindow::from(Cursor::new(Vec::new()))),
+            len,
+            decoded: 0,
+            block_len: 0,
+            block_end: 0,
+            done: false,
+            crc: Crc32::new(),
+        };
+        decoder.next_block()?;
+        Ok(decoder)
+    }
+
+    /// Returns the wrapped reader, which is positioned after the trailer once all bytes
+    /// were read
+    pub fn into_inner(self) -> R {
+        self.input
+    }
+
+    /// Reads the streams of the next block, or compares the checksum after the last one
+    fn next_block(&mut self) -> Result<(), Error> {
+        if self.decoded == self.len {
+            self.done = true;
+            let expected = read_trailer(&mut self.input)?;
+            return self.crc.verify(expected);
+        }
+        let block_len = (self.len - self.decoded).min(BLOCK_SIZE as u64);
+        let mut sizes = [0u64; STREAMS];
+        for size in sizes.iter_mut() {
+            *size = read_varint(&mut self.input)?;
+        }
+        for (stream, (window, &size)) in self.windows.iter_mut().zip(sizes.iter()).enumerate() {
+            let symbols = stream_len(block_len, stream);
+            // the window would hallucinate a byte for empty streams, and no stream takes
+            // more bytes than its longest codes
+            match (symbols, size) {
+                (0, 0) => (),
+                (_, 0) => {
+                    return Err(Error::Truncated {
+                        expected: self.len,
+                        decoded: self.decoded,
+                    })
+                }
+                _ if size > (symbols * self.max_len).div_ceil(8) => {
+                    return Err(Error::Overlong { expected: self.len })
+                }
+                _ => (),
+            }
+            let empty = BitWindow::from(Cursor::new(Vec::new()));
+            let mut data = mem::replace(window, empty).into_inner().into_inner();
+            data.clear();
+            (&mut self.input).take(size).read_to_end(&mut data)?;
+            if data.len() as u64 != size {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            *window = BitWindow::from(Cursor::new(data));
+        }
+        self.block_len = block_len;
+        self.block_end = self.decoded + block_len;
+        Ok(())
+    }
+
+    /// Decodes the next byte from the stream it belongs to
+    fn decode_byte(&mut self) -> Result<u8, Error> {
+        let stream = (self.decoded % STREAMS as u64) as usize;
+        let byte = self
+            .table
+            .decode(&mut self.windows[stream])
+            .map_err(|err| self.truncated(err))?;
+        self.decoded += 1;
+        Ok(byte)
+    }
+
+    /// Reports streams running out of bits as truncated payload
+    fn truncated(&self, err: Error) -> Error {
+        match err {
+            Error::NoBits => Error::Truncated {
+                expected: self.len,
+                decoded: self.decoded,
+            },
+            err => err,
+        }
+    }
+
+    /// Checks that every stream of the current block ended with its last byte
+    fn finish_block(&mut self) -> Result<(), Error> {
+        for (stream, window) in self.windows.iter_mut().enumerate() {
+            if stream_len(self.block_len, stream) == 0 {
+                continue;
+            }
+            let rest = window.initialized();
+            let unread = window.get_mut().fill_buf()?.len();
+            if rest >= 8 || unread > 0 || (rest > 0 && window.show(rest) != 0) {
+                return Err(Error::Overlong { expected: self.len });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Number of bytes in `stream` of a block with `len` bytes
+fn stream_len(len: u64, stream: usize) -> u64 {
+    (len + (STREAMS - 1 - stream) as u64) / STREAMS as u64
+}
+
+impl<R: BufRead> Read for InterleavedDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done {
+            return Ok(0);
+        }
+        let remaining = usize::try_from(self.block_end - self.decoded).unwrap_or(usize::MAX);
+        let buf_len = buf.len().min(remaining);
+        let mut written = 0;
+        // rounds have to start with the first stream
+        while written < buf_len && !self.decoded.is_multiple_of(STREAMS as u64) {
+            buf[written] = self.decode_byte()?;
+            written += 1;
+        }
+        for round in buf[written..buf_len].chunks_exact_mut(STREAMS) {
+            let [first, second, third, fourth] = &mut self.windows;
+            let table = &self.table;
+            // the four lookups do not depend on each other
+            let bytes = (|| {
+                Ok::<_, Error>([
+                    table.decode(first)?,
+                    table.decode(second)?,
+                    table.decode(third)?,
+                    table.decode(fourth)?,
+                ])
+            })();
+            round.copy_from_slice(&bytes.map_err(|err| self.truncated(err))?);
+            written += STREAMS;
+            self.decoded += STREAMS as u64;
+        }
+        while written < buf_len {
+            buf[written] = self.decode_byte()?;
+            written += 1;
+        }
+        self.crc.update(&buf[..written]);
+        if self.decoded == self.block_end {
+            self.finish_block()?;
+            self.next_block()?;
+        }
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hdecode, hencode};
+
+    fn roundtrip(raw: &[u8]) -> Vec<u8> {
+        let mut coded = Vec::new();
+        hencode_interleaved(&mut Cursor::new(raw), &mut coded).expect("encoding failed");
+        let mut out = Vec::new();
+        hdecode(&coded[..], &mut out).expect("decoding failed");
+        assert_eq!(raw, out);
+
+        // reads that do not line up with the streams
+        let mut decoder = InterleavedDecoder::new(&coded[..]).expect("invalid header");
+        let mut out = Vec::new();
+        let mut buf = [0u8; 7];
+        loop {
+            let read = decoder.read(&mut buf[..3]).expect("decoding failed");
+            let more = decoder.read(&mut buf[3..]).expect("decoding failed");
+            out.extend(&buf[..read]);
+            out.extend(&buf[3..3 + more]);
+            if read + more == 0 {
+                break;
+            }
+        }
+        assert_eq!(raw, out);
+        coded
+    }
+
+    #[test]
+    fn source_code() {
+        let raw = include_bytes!("interleaved.rs");
+        let coded = roundtrip(raw);
+        let mut single = Vec::new();
+        hencode(&mut &raw[..], &mut single).expect("encoding failed");
+        // only the sizes of the streams and the partial last bytes are added
+        assert!(coded.len() <= single.len() + 4 * 3);
+    }
+
+    #[test]
+    fn edge_cases() {
+        roundtrip(&[]);
+        roundtrip(&[1]);
+        roundtrip(&[1, 2, 3]);
+        roundtrip(&[7; 5]);
+        roundtrip(&(0..=u8::MAX).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn corrupted() {
+        let raw = b"four streams of the same tree. ".repeat(20);
+        let coded = roundtrip(&raw);
+        assert!(hdecode(&coded[..coded.len() - 1], Vec::new()).is_err());
+
+        // the last byte of the last stream, in front of the trailer
+        let mut flipped = coded.clone();
+        let last = coded.len() - 5;
+        flipped[last] ^= 1;
+        assert!(hdecode(&flipped[..], Vec::new()).is_err());
+    }
+
+    #[test]
+    fn blocks() {
+        // two full blocks and a last block that does not fill all streams
+        let raw: Vec<u8> = (0..2 * BLOCK_SIZE + 6).map(|i| (i % 251) as u8).collect();
+        let coded = roundtrip(&raw);
+        assert!(hdecode(&coded[..coded.len() / 2], Vec::new()).is_err());
+    }
+
+    #[test]
+    fn oversized_stream() {
+        let mut coded = Vec::new();
+        header::write_mode(&mut coded, Mode::Interleaved).expect("io err");
+        Tree::mktree(leaves(&[1; 256]))
+            .store_lengths(&mut coded)
+            .expect("io err");
+        write_varint(&mut coded, 1 << 40).expect("io err");
+        coded.extend(HEADER_END);
+        for _ in 0..STREAMS {
+            write_varint(&mut coded, 1 << 40).expect("io err");
+        }
+        // rejected before the stream is read
+        assert!(matches!(
+            InterleavedDecoder::new(&coded[..]),
+            Err(Error::Overlong { .. })
+        ));
+    }
+}
@@ -1,106 +1,278 @@
+mod adaptive;
 pub mod bitutils;
+mod blocks;
+mod checksum;
+mod chunked;
+mod context;
+mod decoder;
+mod deflate;
+mod encoder;
 mod error;
-mod table;
-mod tree;
+mod header;
+mod index;
+mod inflate;
+mod interleaved;
+mod lz77;
+mod model;
+mod symbols;
+pub mod table;
+mod transform;
+pub mod tree;
 pub mod window;
 
-use crate::bitutils::Symbol;
+pub use crate::adaptive::{hencode_adaptive, AdaptiveDecoder, AdaptiveEncoder};
+use crate::bitutils::{write_varint, Symbol};
+pub use crate::blocks::{
+    hencode_blocks, hencode_blocks_with_checksums, BlockDecoder, DEFAULT_BLOCK_SIZE,
+};
+use crate::checksum::{write_trailer, Crc32};
+pub use crate::chunked::{hencode_chunked, ChunkedDecoder, DEFAULT_CHUNK_SIZE, MAX_CHUNK_SIZE};
+pub use crate::context::{hencode_context, hencode_context_seekable, ContextDecoder};
+pub use crate::decoder::Decoder;
+pub use crate::deflate::{hencode_deflate, hencode_gzip, hencode_zlib};
+pub use crate::encoder::Encoder;
+use crate::encoder::SPOOL_MEMORY;
 pub use crate::error::Error;
+use crate::header::{Mode, HEADER_END};
+pub use crate::index::{hencode_indexed, DEFAULT_INDEX_INTERVAL};
+pub use crate::inflate::{GzDecoder, Inflater, ZlibDecoder};
+pub use crate::interleaved::{hencode_interleaved, InterleavedDecoder};
+pub use crate::lz77::{hencode_lz77, Lz77Decoder, DEFAULT_LEVEL, MAX_LZ77_BLOCK_SIZE};
+pub use crate::model::Model;
+pub use crate::symbols::{hdecode_symbols, hencode_symbols};
 use crate::table::Table;
+pub use crate::transform::{
+    hencode_transformed, TransformDecoder, Transforms, DEFAULT_TRANSFORM_BLOCK_SIZE,
+    MAX_TRANSFORM_BLOCK_SIZE,
+};
 use crate::tree::*;
-use crate::window::BitWindow;
-use std::collections::HashMap;
 use std::io::prelude::*;
-use std::io::BufWriter;
+use std::io::{self, BufReader, BufWriter, SeekFrom};
+use tempfile::SpooledTempFile;
 
+/// Size at which the encoded payload is flushed to the output
+pub(crate) const FLUSH_THRESHOLD: usize = 64 * 1024;
+
+/// Encodes `input` into `output`.
+///
+/// Since encoding needs two passes over the data `input` is spooled through an [`Encoder`]
+/// first. Use [`hencode_seekable`] to avoid this step if the input is seekable already.
 pub fn hencode(input: &mut impl Read, output: &mut impl Write) -> Result<(), Error> {
-    let mut raw = Vec::new();
-    input.read_to_end(&mut raw)?;
-    let mut map = HashMap::new();
-    for byte in raw.iter() {
-        let entry = map.entry(byte).or_insert(0usize);
-        *entry += 1;
-    }
-    let freq: Vec<Tree> = map
-        .into_iter()
-        .map(|(&key, value)| Tree::Leaf(key, value))
-        .collect();
-
-    let tree = Tree::mktree(freq);
-    let map = tree.make_conversion_map();
+    let mut encoder = Encoder::new(output);
+    io::copy(input, &mut encoder)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+/// Encodes `input` starting at its current position into `output`.
+///
+/// The first pass only counts the bytes to build the tree, then `input` is seeked back
+/// and the second pass streams the encoded symbols straight into `output`.
+///
+/// The output only depends on the data, identical input always yields identical output
+/// (see [`Tree::mktree_limited`] for how ties are broken).
+pub fn hencode_seekable(
+    input: &mut (impl Read + Seek),
+    output: &mut impl Write,
+) -> Result<(), Error> {
+    let (counts, crc) = count(input)?;
+    let tree = Tree::mktree(leaves(&counts));
+    let bits = payload_bits(&tree.code_lengths(), &counts).expect("tree contains all bytes");
+    header::write_mode(output, Mode::Static)?;
+    tree.store_lengths(output)?;
+    write_varint(output, counts.iter().sum::<usize>() as u64)?;
+    write_varint(output, bits.div_ceil(8) as u64)?;
+    output.write_all(HEADER_END)?;
+    encode_payload(&tree, input, output)?;
+    write_trailer(output, &crc)?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Encodes `input` into `output` using `tree`, without storing the tree.
+///
+/// Only the number of bytes as varint and the payload are written, they can be decoded
+/// by [`hdecode_payload`] given the table of the same tree.
+///
+/// # Errors
+/// This function returns [`Error::NotInTree`] before writing anything if `input`
+/// contains a byte that is not part of `tree`
+pub fn hencode_payload(
+    tree: &Tree,
+    input: &mut impl Read,
+    output: &mut impl Write,
+) -> Result<(), Error> {
+    let mut spool = SpooledTempFile::new(SPOOL_MEMORY);
+    io::copy(input, &mut spool)?;
+    spool.rewind()?;
+    let (counts, _) = count(&mut spool)?;
+    if let Some(byte) = not_in_tree(tree, &counts) {
+        return Err(Error::NotInTree(byte));
+    }
+    write_varint(output, counts.iter().sum::<usize>() as u64)?;
+    encode_payload(tree, &mut spool, output)
+}
+
+/// Counts all bytes from the current position of `input` and computes their checksum,
+/// seeking back afterwards
+pub(crate) fn count(input: &mut (impl Read + Seek)) -> Result<([usize; 256], Crc32), Error> {
+    let start = input.stream_position()?;
+    let mut counts = [0usize; 256];
+    let mut crc = Crc32::new();
+    let mut reader = BufReader::new(&mut *input);
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        for &byte in buf {
+            counts[byte as usize] += 1;
+        }
+        crc.update(buf);
+        let len = buf.len();
+        reader.consume(len);
+    }
+    drop(reader);
+    input.seek(SeekFrom::Start(start))?;
+    Ok((counts, crc))
+}
+
+/// Creates a leaf for every byte with a non zero count
+pub(crate) fn leaves(counts: &[usize; 256]) -> Vec<Tree> {
+    counts
+        .iter()
+        .enumerate()
+        .filter(|(_, &count)| count > 0)
+        .map(|(byte, &count)| Tree::Leaf(byte as u8, count))
+        .collect()
+}
+
+/// Number of bits needed to encode bytes with `counts` using the code described by `lengths`
+///
+/// # Returns
+/// [None] if a byte that has to be encoded has no code
+pub(crate) fn payload_bits(lengths: &[u8], counts: &[usize; 256]) -> Option<usize> {
+    counts
+        .iter()
+        .enumerate()
+        .filter(|(_, &count)| count > 0)
+        .map(|(byte, &count)| match lengths.get(byte) {
+            Some(&len) if len > 0 => Some(count * len as usize),
+            _ => None,
+        })
+        .sum()
+}
 
-    tree.store(output)?;
+/// Returns the first byte with a non zero count that has no code in `tree`
+fn not_in_tree(tree: &Tree, counts: &[usize; 256]) -> Option<u8> {
+    let map = tree.make_conversion_map();
+    (0..=u8::MAX).find(|byte| counts[*byte as usize] > 0 && !map.contains_key(byte))
+}
 
+/// Writes the encoded bytes of `input`, which must only contain bytes of `tree`
+///
+/// The last byte is padded with zeros, the number of encoded bytes has to be stored
+/// separately.
+pub(crate) fn encode_payload(
+    tree: &Tree,
+    input: &mut impl Read,
+    output: &mut impl Write,
+) -> Result<(), Error> {
+    let map = tree.make_conversion_map();
     let mut encoded = Symbol {
-        bytes: Vec::with_capacity(raw.len()),
+        bytes: Vec::with_capacity(FLUSH_THRESHOLD + 1),
         bitpos: 0,
         bytepos: 0,
     };
-
-    for byte in raw.iter() {
-        encoded.append_sym(
-            map.get(byte)
-                .expect("every byte variant in raw must be contained in map"),
-        );
+    let mut reader = BufReader::new(input);
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        for byte in buf {
+            encoded.append_sym(
+                map.get(byte)
+                    .expect("every byte variant in input must be contained in map"),
+            );
+            if encoded.bytepos >= FLUSH_THRESHOLD {
+                encoded.write_complete(output)?;
+            }
+        }
+        let len = buf.len();
+        reader.consume(len);
     }
-    assert_eq!(output.write(&[8u8 - encoded.bitpos as u8])?, 1);
     output.write_all(&encoded.bytes)?;
     output.flush()?;
 
     Ok(())
 }
 
-pub fn hdecode(mut input: impl BufRead, output: impl Write) -> Result<(), Error> {
-    let mut output = BufWriter::new(output);
-    let root = Tree::try_load(&mut input)?;
-    let table = Table::from_tree_root(&root).expect("root aint root");
-    let mut padding = [0u8];
-    input.read_exact(&mut padding)?;
-    if input.fill_buf()?.is_empty() {
-        return Ok(());
-    }
-    let padding = padding[0] as usize;
-    let mut window: BitWindow<_> = input.into();
-    let consume_err_on_read_padding =
-        |window: &mut BitWindow<_>, bits: usize, padding: usize| -> Result<_, Error> {
-            if window.consume(bits)? && window.initialized() < padding {
-                Err(Error::NoBits)
-            } else {
-                Ok(())
-            }
-        };
-    loop {
-        let index = window.show(8);
-        let byte = match table[index] {
-            table::Entry::Map { byte, bitlen } => {
-                consume_err_on_read_padding(&mut window, bitlen, padding)?;
-                byte
-            }
-            table::Entry::Subtable { offset, bitdepth } => {
-                consume_err_on_read_padding(&mut window, 8, padding)?;
-                // handle subtable entry
-                let index = window.show(bitdepth);
-                let entry = table[index + offset];
-                let table::Entry::Map { byte, bitlen } = entry else {
-                    unimplemented!("dont allow nested subtables");
-                };
-
-                consume_err_on_read_padding(&mut window, bitlen, padding)?;
-                byte
-            }
-        };
+/// Decodes `input` into `output`.
+///
+/// Handles all modes written by the encoders of this crate. Use [`Decoder`],
+/// [`BlockDecoder`], [`AdaptiveDecoder`], [`ContextDecoder`], [`TransformDecoder`]
+/// or [`Lz77Decoder`] to read the decoded data lazily instead. Chunked data is decoded
+/// using one thread per available core.
+///
+/// # Errors
+/// Besides errors of `input` and `output`, this function returns
+/// [`Error::ChecksumMismatch`] if the decoded data does not match the CRC32 stored
+/// after the payload, which means the encoded data was corrupted.
+pub fn hdecode(input: impl BufRead, output: impl Write) -> Result<(), Error> {
+    hdecode_with_threads(input, output, chunked::default_threads())
+}
 
-        assert_eq!(output.write(&[byte])?, 1);
-        if window.initialized() == padding {
-            output.flush()?;
-            return Ok(());
+/// Decodes `input` into `output` like [`hdecode`], decoding chunked data using
+/// `threads` threads.
+///
+/// # Panics
+/// This function panics if `threads` is 0
+pub fn hdecode_with_threads(
+    mut input: impl BufRead,
+    output: impl Write,
+    threads: usize,
+) -> Result<(), Error> {
+    let mut output = BufWriter::new(output);
+    match header::read_mode(&mut input)? {
+        mode @ (Mode::Static | Mode::Indexed) => {
+            io::copy(&mut Decoder::after_mode(input, mode)?, &mut output)?
         }
-    }
+        Mode::Blocks => io::copy(&mut BlockDecoder::after_mode(input)?, &mut output)?,
+        Mode::Adaptive => io::copy(&mut AdaptiveDecoder::after_mode(input)?, &mut output)?,
+        Mode::Context => io::copy(&mut ContextDecoder::after_mode(input)?, &mut output)?,
+        Mode::Transform => io::copy(&mut TransformDecoder::after_mode(input)?, &mut output)?,
+        Mode::Lz77 => io::copy(&mut Lz77Decoder::after_mode(input)?, &mut output)?,
+        Mode::Symbols => io::copy(&mut symbols::after_mode::<u8, _>(input)?, &mut output)?,
+        Mode::Interleaved => io::copy(&mut InterleavedDecoder::after_mode(input)?, &mut output)?,
+        Mode::Chunked => io::copy(
+            &mut ChunkedDecoder::after_mode(input, threads)?,
+            &mut output,
+        )?,
+    };
+    output.flush()?;
+    Ok(())
+}
+
+/// Decodes a payload written by [`hencode_payload`] from `input` into `output` using `table`
+pub fn hdecode_payload(
+    table: &Table,
+    input: impl BufRead,
+    output: impl Write,
+) -> Result<(), Error> {
+    let mut output = BufWriter::new(output);
+    let mut decoder = Decoder::with_table(table, input)?;
+    io::copy(&mut decoder, &mut output)?;
+    output.flush()?;
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{hdecode, hencode, Error};
+    use crate::table::Table;
+    use crate::tree::Tree;
+    use crate::{hdecode, hdecode_payload, hencode, hencode_payload, hencode_seekable, Error};
+    use std::io::Cursor;
     const RAW: &str = r#"
 In computer science and information theory, a Huffman code is a particular type of optimal prefix code that is commonly used for lossless data compression. The process of finding or using such a code is Huffman coding, an algorithm developed by David A. Huffman while he was a Sc.D. student at MIT, and published in the 1952 paper "A Method for the Construction of Minimum-Redundancy Codes".[1]
 
@@ -131,4 +303,134 @@ The output from Huffman's algorithm can be viewed as a variable-length code tabl
     fn encode() {
         create_coded().unwrap();
     }
+
+    #[test]
+    fn checksum_mismatch() {
+        let mut coded = create_coded().expect("encoding failed");
+        let last = coded.len() - 1;
+        coded[last] ^= 1;
+        assert!(matches!(
+            hdecode(&coded[..], Vec::new()),
+            Err(Error::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn corrupted_payloads() {
+        type Encode = fn(&mut &[u8], &mut Vec<u8>) -> Result<(), Error>;
+        let encoders: [Encode; 7] = [
+            |input, output| hencode(input, output),
+            |input, output| crate::hencode_blocks(input, output, 256),
+            |input, output| crate::hencode_adaptive(input, output),
+            |input, output| crate::hencode_context(input, output),
+            |input, output| crate::hencode_transformed(input, output, crate::Transforms::ALL, 256),
+            |input, output| crate::hencode_lz77(input, output, 6),
+            |input, output| crate::hencode_symbols(input, output),
+        ];
+        for encode in encoders {
+            let mut coded = Vec::new();
+            encode(&mut RAW.as_bytes(), &mut coded).expect("encoding failed");
+            // a single flipped bit in the middle of the payload
+            let middle = coded.len() / 2;
+            coded[middle] ^= 0b0001_0000;
+            assert!(hdecode(&coded[..], Vec::new()).is_err());
+        }
+    }
+
+    fn roundtrip(raw: &[u8]) {
+        let mut coded = Vec::new();
+        hencode(&mut &raw[..], &mut coded).expect("encoding failed");
+        let mut out = Vec::new();
+        hdecode(&coded[..], &mut out).expect("decoding failed");
+        assert_eq!(raw, out);
+    }
+
+    #[test]
+    fn empty_roundtrip() {
+        roundtrip(&[]);
+    }
+
+    #[test]
+    fn single_byte_roundtrip() {
+        for byte in [0, 1, 42, 255] {
+            roundtrip(&[byte]);
+            roundtrip(&[byte; 7]);
+            roundtrip(&[byte; 4096]);
+        }
+    }
+
+    #[test]
+    fn fibonacci_roundtrip() {
+        // unlimited huffman codes would be 19 bits long for this distribution
+        let mut raw = Vec::new();
+        let mut fib = (1, 1);
+        for byte in 0..20u8 {
+            raw.extend(std::iter::repeat_n(byte, fib.0));
+            fib = (fib.1, fib.0 + fib.1);
+        }
+        roundtrip(&raw);
+    }
+
+    #[test]
+    fn reproducible() {
+        // changes to this output break content addressed caches relying on stable output
+        let mut expected = b"----- rxh tree start V9 -----\n".to_vec();
+        // static mode
+        expected.push(0);
+        // code lengths: a=1, b=c=d=r=3
+        expected.extend([115, 0, 96, 1, 3, 3, 3, 0, 12, 3]);
+        // number of bytes and size of the payload
+        expected.extend([11, 3]);
+        expected.extend(b"\n----- rxh tree end V9 -----\n");
+        // 0 100 111 0 101 0 110 0 100 111 0, padded with a zero
+        expected.extend([0b01001110, 0b10101100, 0b10011100]);
+        // crc32 of the original data
+        expected.extend([0xb7, 0xf9, 0xea, 0x17]);
+
+        let mut coded = Vec::new();
+        hencode(&mut &b"abracadabra"[..], &mut coded).expect("encoding failed");
+        assert_eq!(expected, coded);
+    }
+
+    #[test]
+    fn payload_roundtrip() {
+        let sample = b"{\"id\": 0, \"name\": \"sample\"}";
+        let freq = (0..=u8::MAX)
+            .filter(|byte| sample.contains(byte))
+            .map(|byte| Tree::Leaf(byte, sample.iter().filter(|&&b| b == byte).count()))
+            .collect();
+        let tree = Tree::mktree(freq);
+        let table = Table::from_tree_root(&tree).expect("valid tree");
+
+        let message = b"{\"name\": \"same\", \"id\": 0}";
+        let mut coded = Vec::new();
+        hencode_payload(&tree, &mut &message[..], &mut coded).expect("encoding failed");
+        assert!(coded.len() < message.len());
+        // decoding stops after the payload
+        coded.extend(b"trailing data");
+        let mut out = Vec::new();
+        hdecode_payload(&table, &coded[..], &mut out).expect("decoding failed");
+        assert_eq!(&message[..], &out);
+    }
+
+    #[test]
+    fn payload_not_in_tree() {
+        let tree = Tree::mktree(vec![Tree::Leaf(b'a', 1), Tree::Leaf(b'b', 1)]);
+        let mut coded = Vec::new();
+        let result = hencode_payload(&tree, &mut &b"abc"[..], &mut coded);
+        assert!(matches!(result, Err(Error::NotInTree(b'c'))));
+        assert!(coded.is_empty());
+    }
+
+    #[test]
+    fn encode_seekable() {
+        let coded = create_coded().expect("encoding failed");
+        let mut input = Cursor::new(RAW.as_bytes());
+        let mut out = Vec::new();
+        hencode_seekable(&mut input, &mut out).expect("encoding failed");
+        assert_eq!(
+            coded, out,
+            "seekable encoding differs from spooled encoding"
+        );
+    }
 }
@@ -0,0 +1,552 @@
+use std::collections::HashMap;
+use std::io::{self, prelude::*};
+
+use crate::bitutils::{read_varint, write_varint, Symbol};
+use crate::checksum::{read_trailer, write_trailer, Crc32};
+use crate::header::{self, Mode, HEADER_END};
+use crate::table::Table;
+use crate::tree::{Key, Tree};
+use crate::window::BitWindow;
+use crate::Error;
+
+/// Compression level that trades speed for size similar to the default of zlib
+pub const DEFAULT_LEVEL: u8 = 6;
+/// Matches may reach this many bytes back
+pub(crate) const WINDOW_SIZE: usize = 32 * 1024;
+pub(crate) const MIN_MATCH: usize = 3;
+pub(crate) const MAX_MATCH: usize = 258;
+/// Input bytes per block, each block gets its own trees. [`Lz77Decoder`] rejects blocks
+/// that decode to more bytes.
+pub const MAX_LZ77_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// literal/length symbol marking the end of a block
+pub(crate) const END_OF_BLOCK: u16 = 256;
+/// first literal/length symbol standing for a length
+const FIRST_LENGTH: u16 = 257;
+
+/// Shortest length of every length code, as in RFC 1951 3.2.5
+pub(crate) const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+/// Extra bits following every length code
+pub(crate) const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+/// Shortest distance of every distance code
+pub(crate) const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+/// Extra bits following every distance code
+pub(crate) const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Maximum number of chain entries visited and the match length at which the search
+/// stops early, for every level
+const LEVELS: [(usize, usize); 10] = [
+    (0, 0),
+    (4, 8),
+    (8, 16),
+    (16, 32),
+    (32, 64),
+    (64, 128),
+    (128, 258),
+    (256, 258),
+    (1024, 258),
+    (4096, 258),
+];
+
+const HASH_BITS: usize = 15;
+/// marks empty hash chain entries
+const NONE: usize = usize::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Token {
+    Literal(u8),
+    Match { length: u16, distance: u16 },
+}
+
+/// Returns the code of `value` in a table of `bases` and the value of its extra bits
+pub(crate) fn code_of(bases: &[u16], value: u16) -> (usize, u16) {
+    let code = bases.partition_point(|&base| base <= value) - 1;
+    (code, value - bases[code])
+}
+
+fn hash(data: &[u8], pos: usize) -> usize {
+    ((data[pos] as usize) << 10 ^ (data[pos + 1] as usize) << 5 ^ data[pos + 2] as usize)
+        & ((1 << HASH_BITS) - 1)
+}
+
+/// Hash chains linking every position to the previous one starting with the same bytes
+struct Chains {
+    head: Vec<usize>,
+    prev: Vec<usize>,
+}
+
+impl Chains {
+    fn new(len: usize) -> Self {
+        Chains {
+            head: vec![NONE; 1 << HASH_BITS],
+            prev: vec![NONE; len],
+        }
+    }
+
+    fn insert(&mut self, data: &[u8], pos: usize) {
+        if pos + MIN_MATCH <= data.len() {
+            let hash = hash(data, pos);
+            self.prev[pos] = self.head[hash];
+            self.head[hash] = pos;
+        }
+    }
+}
+
+/// Finds matches in `data[start..]` using hash chains, `data[..start]` is history that
+/// matches may refer to.
+///
+/// `level` (0 to 9) controls how many earlier positions are tried for every match,
+/// level 0 emits literals only.
+///
+/// # Panics
+/// This function panics if `level` is larger than 9
+pub(crate) fn tokenize(data: &[u8], start: usize, level: u8) -> Vec<Token> {
+    let (max_chain, nice_length) = LEVELS[level as usize];
+    let mut chains = Chains::new(data.len());
+    for pos in start.saturating_sub(WINDOW_SIZE)..start {
+        chains.insert(data, pos);
+    }
+
+    let mut tokens = Vec::new();
+    let mut pos = start;
+    while pos < data.len() {
+        let max_length = MAX_MATCH.min(data.len() - pos);
+        let (mut length, mut distance) = (0, 0);
+        if max_chain > 0 && max_length >= MIN_MATCH {
+            let mut candidate = chains.head[hash(data, pos)];
+            let mut chain = max_chain;
+            while candidate != NONE && pos - candidate <= WINDOW_SIZE && chain > 0 {
+                let common = data[candidate..]
+                    .iter()
+                    .zip(&data[pos..pos + max_length])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if common > length {
+                    (length, distance) = (common, pos - candidate);
+                    if length >= nice_length {
+                        break;
+                    }
+                }
+                candidate = chains.prev[candidate];
+                chain -= 1;
+            }
+        }
+
+        if length >= MIN_MATCH {
+            tokens.push(Token::Match {
+                length: length as u16,
+                distance: distance as u16,
+            });
+        } else {
+            tokens.push(Token::Literal(data[pos]));
+            length = 1;
+        }
+        for pos in pos..pos + length {
+            chains.insert(data, pos);
+        }
+        pos += length;
+    }
+    tokens
+}
+
+/// Encodes `input` into `output` using LZ77 followed by huffman coding.
+///
+/// Repeated strings within the last 32 KiB are replaced by their length and distance.
+/// Literals and lengths share one alphabet, distances use a second one, both are
+/// huffman coded with a tree per block like in RFC 1951.
+///
+/// `level` goes from 0 (no matches, fastest) to 9 (most thorough search).
+///
+/// Each block is stored as a marker byte, the code lengths of both trees, the length of
+/// the payload and the payload, which ends with an end of block symbol.
+///
+/// # Panics
+/// This function panics if `level` is larger than 9
+pub fn hencode_lz77(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    level: u8,
+) -> Result<(), Error> {
+    assert!(level <= 9, "levels go from 0 to 9");
+    header::write_mode(output, Mode::Lz77)?;
+    output.write_all(HEADER_END)?;
+
+    let mut data = Vec::with_capacity(WINDOW_SIZE + MAX_LZ77_BLOCK_SIZE);
+    let mut payload = Vec::new();
+    let mut crc = Crc32::new();
+    loop {
+        // keep the end of the previous block as history
+        let start = data.len().min(WINDOW_SIZE);
+        data.drain(..data.len() - start);
+        input
+            .by_ref()
+            .take(MAX_LZ77_BLOCK_SIZE as u64)
+            .read_to_end(&mut data)?;
+        if data.len() == start {
+            break;
+        }
+        crc.update(&data[start..]);
+        let tokens = tokenize(&data, start, level);
+
+        let mut literals: HashMap<u16, usize> = HashMap::from([(END_OF_BLOCK, 1)]);
+        let mut distances: HashMap<u8, usize> = HashMap::new();
+        for token in tokens.iter() {
+            match *token {
+                Token::Literal(byte) => *literals.entry(byte as u16).or_default() += 1,
+                Token::Match { length, distance } => {
+                    let (code, _) = code_of(&LENGTH_BASE, length);
+                    *literals.entry(FIRST_LENGTH + code as u16).or_default() += 1;
+                    let (code, _) = code_of(&DISTANCE_BASE, distance);
+                    *distances.entry(code as u8).or_default() += 1;
+                }
+            }
+        }
+        let literals = mktree(&literals);
+        let distances = mktree(&distances);
+
+        output.write_all(&[1])?;
+        literals.store_lengths(output)?;
+        distances.store_lengths(output)?;
+        payload.clear();
+        encode_tokens(&tokens, &literals, &distances, &mut payload);
+        write_varint(output, payload.len() as u64)?;
+        output.write_all(&payload)?;
+    }
+    output.write_all(&[0])?;
+    write_trailer(output, &crc)?;
+    output.flush()?;
+    Ok(())
+}
+
+fn mktree<K: Key>(counts: &HashMap<K, usize>) -> Tree<K> {
+    Tree::mktree(
+        counts
+            .iter()
+            .map(|(&key, &count)| Tree::Leaf(key, count))
+            .collect(),
+    )
+}
+
+/// Writes the encoded `tokens` followed by the end of block symbol into `output`
+fn encode_tokens(
+    tokens: &[Token],
+    literals: &Tree<u16>,
+    distances: &Tree<u8>,
+    output: &mut Vec<u8>,
+) {
+    let literals = literals.make_conversion_map();
+    let distances = distances.make_conversion_map();
+    let mut encoded = Symbol {
+        bytes: Vec::new(),
+        bitpos: 0,
+        bytepos: 0,
+    };
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => encoded.append_sym(&literals[&(byte as u16)]),
+            Token::Match { length, distance } => {
+                let (code, extra) = code_of(&LENGTH_BASE, length);
+                encoded.append_sym(&literals[&(FIRST_LENGTH + code as u16)]);
+                encoded.append_bits(extra as u32, LENGTH_EXTRA[code]);
+                let (code, extra) = code_of(&DISTANCE_BASE, distance);
+                encoded.append_sym(&distances[&(code as u8)]);
+                encoded.append_bits(extra as u32, DISTANCE_EXTRA[code]);
+            }
+        }
+    }
+    encoded.append_sym(&literals[&END_OF_BLOCK]);
+    output.append(&mut encoded.bytes);
+}
+
+/// Reads `bits` bits from `window`, most significant bit first
+pub(crate) fn read_bits<R: BufRead>(window: &mut BitWindow<R>, bits: u8) -> Result<u16, Error> {
+    let mut value = 0;
+    let mut left = bits as usize;
+    while left > 0 {
+        // the window only guarantees 8 visible bits
+        let amt = left.min(8);
+        value = value << amt | window.show(amt) as u16;
+        window.consume(amt)?;
+        left -= amt;
+    }
+    Ok(value)
+}
+
+/// Pull based decoder for data encoded by [`hencode_lz77`].
+///
+/// Decodes one block at a time and keeps the last 32 KiB of output for matches
+/// reaching back into earlier blocks.
+pub struct Lz77Decoder<R: BufRead> {
+    input: R,
+    /// decoded data, starting with the history kept from earlier blocks
+    buffer: Vec<u8>,
+    /// number of bytes in `buffer` that were returned already
+    position: usize,
+    done: bool,
+    crc: Crc32,
+}
+
+impl<R: BufRead> Lz77Decoder<R> {
+    /// Reads the header from `input` and prepares decoding the blocks
+    ///
+    /// # Errors
+    /// This method returns an error if `input` does not start with a valid LZ77 mode header
+    pub fn new(mut input: R) -> Result<Self, Error> {
+        if header::read_mode(&mut input)? != Mode::Lz77 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file does not use LZ77, use hdecode instead",
+            )
+            .into());
+        }
+        Lz77Decoder::after_mode(input)
+    }
+
+    /// Prepares decoding LZ77 mode data from `input` that is positioned after the mode
+    pub(crate) fn after_mode(mut input: R) -> Result<Self, Error> {
+        header::read_end(&mut input)?;
+        Ok(Lz77Decoder {
+            input,
+            buffer: Vec::new(),
+            position: 0,
+            done: false,
+            crc: Crc32::new(),
+        })
+    }
+
+    fn next_block(&mut self) -> Result<(), Error> {
+        let mut marker = [0u8];
+        self.input.read_exact(&mut marker)?;
+        match marker[0] {
+            0 => {
+                self.done = true;
+                return self.crc.verify(read_trailer(&mut self.input)?);
+            }
+            1 => (),
+            _ => return Err(invalid_data("unknown block marker").into()),
+        }
+        let literals = load_table::<u16>(&mut self.input)?;
+        let distances = load_table::<u8>(&mut self.input)?;
+        let len = read_varint(&mut self.input)?;
+        let mut payload = Vec::new();
+        (&mut self.input).take(len).read_to_end(&mut payload)?;
+        if payload.len() as u64 != len {
+            return Err(Error::NoBits);
+        }
+
+        let history = self.buffer.len().min(WINDOW_SIZE);
+        self.buffer.drain(..self.buffer.len() - history);
+        self.position = history;
+        let mut window: BitWindow<&[u8]> = (&payload[..]).into();
+        loop {
+            // a match may overshoot the limit by less than `MAX_MATCH` bytes
+            if self.buffer.len() - history > MAX_LZ77_BLOCK_SIZE {
+                return Err(invalid_data("block is larger than the maximum block size").into());
+            }
+            let symbol = literals.decode(&mut window)?;
+            match symbol {
+                0..=255 => self.buffer.push(symbol as u8),
+                END_OF_BLOCK => {
+                    if window.bytes_consumed() < len {
+                        return Err(invalid_data("block payload is longer than its content").into());
+                    }
+                    self.crc.update(&self.buffer[history..]);
+                    return Ok(());
+                }
+                _ => {
+                    let code = (symbol - FIRST_LENGTH) as usize;
+                    if code >= LENGTH_BASE.len() {
+                        return Err(invalid_data("invalid length code").into());
+                    }
+                    let length = LENGTH_BASE[code] + read_bits(&mut window, LENGTH_EXTRA[code])?;
+                    let code = distances.decode(&mut window)? as usize;
+                    if code >= DISTANCE_BASE.len() {
+                        return Err(invalid_data("invalid distance code").into());
+                    }
+                    let distance = (DISTANCE_BASE[code]
+                        + read_bits(&mut window, DISTANCE_EXTRA[code])?)
+                        as usize;
+                    if distance > self.buffer.len() {
+                        return Err(invalid_data("distance reaches before the data").into());
+                    }
+                    // matches may overlap the bytes they produce
+                    let start = self.buffer.len() - distance;
+                    for pos in start..start + length as usize {
+                        self.buffer.push(self.buffer[pos]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn load_table<K: Key>(input: &mut impl Read) -> Result<Table<K>, io::Error> {
+    let lengths = Tree::<K>::load_sparse_lengths(input)?;
+    Table::from_sparse_lengths(&lengths).ok_or_else(|| invalid_data("code cannot be decoded"))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl<R: BufRead> Read for Lz77Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.position == self.buffer.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.next_block()?;
+        }
+        let read = (self.buffer.len() - self.position).min(buf.len());
+        buf[..read].copy_from_slice(&self.buffer[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hdecode, hencode};
+
+    fn roundtrip(raw: &[u8], level: u8) -> Vec<u8> {
+        let mut coded = Vec::new();
+        hencode_lz77(&mut &raw[..], &mut coded, level).expect("encoding failed");
+        let mut out = Vec::new();
+        hdecode(&coded[..], &mut out).expect("decoding failed");
+        assert_eq!(raw, out);
+        coded
+    }
+
+    #[test]
+    fn codes() {
+        assert_eq!((0, 0), code_of(&LENGTH_BASE, 3));
+        assert_eq!((8, 1), code_of(&LENGTH_BASE, 12));
+        assert_eq!((27, 30), code_of(&LENGTH_BASE, 257));
+        assert_eq!((28, 0), code_of(&LENGTH_BASE, 258));
+        assert_eq!((29, 8191), code_of(&DISTANCE_BASE, 32768));
+    }
+
+    #[test]
+    fn overlapping_match() {
+        let tokens = tokenize(b"abcabcabcabcx", 0, 6);
+        assert_eq!(
+            vec![
+                Token::Literal(b'a'),
+                Token::Literal(b'b'),
+                Token::Literal(b'c'),
+                Token::Match {
+                    length: 9,
+                    distance: 3
+                },
+                Token::Literal(b'x'),
+            ],
+            tokens
+        );
+    }
+
+    #[test]
+    fn levels() {
+        let raw = include_bytes!("lz77.rs");
+        let mut plain = Vec::new();
+        hencode(&mut &raw[..], &mut plain).expect("encoding failed");
+        let sizes: Vec<usize> = [0, 1, 6, 9]
+            .into_iter()
+            .map(|level| roundtrip(raw, level).len())
+            .collect();
+        assert!(sizes[1] < plain.len() * 2 / 3);
+        assert!(sizes.windows(2).all(|pair| pair[1] <= pair[0]));
+    }
+
+    #[test]
+    fn edge_cases() {
+        roundtrip(&[], 6);
+        roundtrip(&[1], 6);
+        roundtrip(&[0; 100_000], 6);
+        // matches reaching into the previous block
+        let raw: Vec<u8> = (0..MAX_LZ77_BLOCK_SIZE + 5000)
+            .map(|num| (num % 251) as u8)
+            .collect();
+        roundtrip(&raw, 1);
+    }
+
+    /// Encodes a single block of `tokens` that decode to `raw`, with `extra` zero bytes
+    /// after its payload
+    fn block(tokens: &[Token], raw: &[u8], extra: usize) -> Vec<u8> {
+        let mut literals = HashMap::from([(END_OF_BLOCK, 1)]);
+        let mut distances = HashMap::new();
+        for token in tokens {
+            match *token {
+                Token::Literal(byte) => *literals.entry(byte as u16).or_default() += 1,
+                Token::Match { length, distance } => {
+                    let (code, _) = code_of(&LENGTH_BASE, length);
+                    *literals.entry(FIRST_LENGTH + code as u16).or_default() += 1;
+                    let (code, _) = code_of(&DISTANCE_BASE, distance);
+                    *distances.entry(code as u8).or_default() += 1;
+                }
+            }
+        }
+        let literals = mktree(&literals);
+        let distances = mktree(&distances);
+        let mut payload = Vec::new();
+        encode_tokens(tokens, &literals, &distances, &mut payload);
+        payload.extend(vec![0; extra]);
+
+        let mut coded = Vec::new();
+        header::write_mode(&mut coded, Mode::Lz77).expect("io err");
+        coded.extend(HEADER_END);
+        coded.push(1);
+        literals.store_lengths(&mut coded).expect("io err");
+        distances.store_lengths(&mut coded).expect("io err");
+        write_varint(&mut coded, payload.len() as u64).expect("io err");
+        coded.extend(payload);
+        coded.push(0);
+        let mut crc = Crc32::new();
+        crc.update(raw);
+        write_trailer(&mut coded, &crc).expect("io err");
+        coded
+    }
+
+    #[test]
+    fn oversized_block() {
+        let mut tokens = vec![Token::Literal(7)];
+        let run = Token::Match {
+            length: MAX_MATCH as u16,
+            distance: 1,
+        };
+        tokens.extend(vec![run; MAX_LZ77_BLOCK_SIZE / MAX_MATCH + 1]);
+        let coded = block(&tokens, &[], 0);
+        // a few kilobytes of payload
+        assert!(coded.len() < MAX_LZ77_BLOCK_SIZE / 100);
+        let err = hdecode(&coded[..], Vec::new()).expect_err("block is too large");
+        assert!(matches!(err, Error::IoError(err) if err.kind() == io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn padded_payload() {
+        let raw = b"abcabcabcabcx";
+        let tokens = tokenize(raw, 0, 6);
+        let mut out = Vec::new();
+        hdecode(&block(&tokens, raw, 0)[..], &mut out).expect("decoding failed");
+        assert_eq!(raw, &out[..]);
+        for extra in 1..8 {
+            let err = hdecode(&block(&tokens, raw, extra)[..], Vec::new())
+                .expect_err("payload is padded");
+            assert!(matches!(err, Error::IoError(err) if err.kind() == io::ErrorKind::InvalidData));
+        }
+    }
+}
@@ -1,39 +1,200 @@
 use huffman::*;
 use std::env;
-use std::fs::OpenOptions;
-use std::io::BufReader;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter};
 use std::process::exit;
 
+const USAGE: &str =
+    "Usage: huffmann <file> [-d] [--model <model> | --blocks <size> | --adaptive | --context | --bwt | --lz77 <level> | --gzip <level> | --index <interval> | --threads <count> | --interleaved]
+       huffmann train <model> <sample>...";
+
+fn create(path: &str) -> Result<File, Error> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(path)?)
+}
+
+fn open(path: &str) -> Result<File, Error> {
+    Ok(OpenOptions::new().read(true).open(path)?)
+}
+
 fn main() -> Result<(), Error> {
     let mut args = env::args().skip(1);
-    if let Some(arg) = args.next() {
-        if arg == "--help" {
-            println!("Usage: huffmann <file> [-d]");
-            return Ok(());
-        }
-        let mut input = OpenOptions::new().read(true).open(&arg)?;
+    let Some(arg) = args.next() else {
+        eprintln!("[0] Please supply a file argument");
+        exit(1);
+    };
+    if arg == "--help" {
+        println!("{USAGE}");
+        return Ok(());
+    }
 
-        if let Some(option) = args.next() {
-            if option == "-d" {
-                let mut output = OpenOptions::new()
-                    .create(true)
-                    .write(true)
-                    .open(&arg[..arg.len() - 4])?;
+    if arg == "train" {
+        let Some(model) = args.next() else {
+            eprintln!("[1] Please supply a model file argument");
+            exit(1);
+        };
+        let samples = args
+            .map(|sample| open(&sample))
+            .collect::<Result<Vec<_>, _>>()?;
+        let trained = Model::train(samples.into_iter().map(BufReader::new))?;
+        trained.store(&mut create(&model)?)?;
+        return Ok(());
+    }
 
-                let mut input = BufReader::new(input);
-                hdecode(&mut input, &mut output)?;
-                return Ok(());
+    let mut decode = false;
+    let mut model = None;
+    let mut blocks = None;
+    let mut adaptive = false;
+    let mut context = false;
+    let mut bwt = false;
+    let mut lz77 = None;
+    let mut gzip = None;
+    let mut index = None;
+    let mut threads = None;
+    let mut interleaved = false;
+    while let Some(option) = args.next() {
+        match option.as_str() {
+            "-d" => decode = true,
+            "--model" => {
+                let Some(path) = args.next() else {
+                    eprintln!("[2] Please supply a model file after --model");
+                    exit(1);
+                };
+                model = Some(Model::load(&mut BufReader::new(open(&path)?))?);
+            }
+            "--adaptive" => adaptive = true,
+            "--context" => context = true,
+            "--bwt" => bwt = true,
+            "--interleaved" => interleaved = true,
+            "--lz77" => {
+                let Some(level) = args
+                    .next()
+                    .and_then(|level| level.parse().ok())
+                    .filter(|&level| level <= 9)
+                else {
+                    eprintln!("[6] Please supply a level from 0 to 9 after --lz77");
+                    exit(1);
+                };
+                lz77 = Some(level);
+            }
+            "--gzip" => {
+                let Some(level) = args
+                    .next()
+                    .and_then(|level| level.parse().ok())
+                    .filter(|&level| level <= 9)
+                else {
+                    eprintln!("[7] Please supply a level from 0 to 9 after --gzip");
+                    exit(1);
+                };
+                gzip = Some(level);
+            }
+            "--index" => {
+                let Some(interval) = args
+                    .next()
+                    .and_then(|interval| interval.parse().ok())
+                    .filter(|&interval| interval > 0)
+                else {
+                    eprintln!("[8] Please supply an interval greater than 0 after --index");
+                    exit(1);
+                };
+                index = Some(interval);
+            }
+            "--threads" => {
+                let Some(count) = args
+                    .next()
+                    .and_then(|count| count.parse().ok())
+                    .filter(|&count| count > 0)
+                else {
+                    eprintln!("[9] Please supply a thread count greater than 0 after --threads");
+                    exit(1);
+                };
+                threads = Some(count);
+            }
+            "--blocks" => {
+                let Some(size) = args
+                    .next()
+                    .and_then(|size| size.parse().ok())
+                    .filter(|&size| size > 0)
+                else {
+                    eprintln!("[4] Please supply a block size greater than 0 after --blocks");
+                    exit(1);
+                };
+                blocks = Some(size);
+            }
+            _ => {
+                eprintln!("[3] Unknown option '{option}'\n{USAGE}");
+                exit(1);
             }
         }
+    }
 
-        let mut output = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(format!("{}.rxc", arg))?;
-        hencode(&mut input, &mut output)?;
-        Ok(())
-    } else {
-        eprintln!("[0] Please supply a file argument");
+    if [
+        model.is_some(),
+        blocks.is_some(),
+        adaptive,
+        context,
+        bwt,
+        lz77.is_some(),
+        gzip.is_some(),
+        index.is_some(),
+        threads.is_some(),
+        interleaved,
+    ]
+    .iter()
+    .filter(|&&set| set)
+    .count()
+        > 1
+    {
+        eprintln!(
+            "[5] --model, --blocks, --adaptive, --context, --bwt, --lz77, --gzip, --index, --threads and --interleaved cannot be combined\n{USAGE}"
+        );
         exit(1);
     }
+
+    let mut input = open(&arg)?;
+    if decode {
+        let input = BufReader::new(input);
+        match (model, arg.strip_suffix(".gz"), threads) {
+            (Some(model), ..) => model.decode(input, &mut create(&arg[..arg.len() - 4])?)?,
+            (None, Some(name), _) => {
+                io::copy(&mut GzDecoder::new(input)?, &mut create(name)?)?;
+            }
+            (None, None, Some(threads)) => {
+                hdecode_with_threads(input, &mut create(&arg[..arg.len() - 4])?, threads)?
+            }
+            (None, None, None) => hdecode(input, &mut create(&arg[..arg.len() - 4])?)?,
+        }
+    } else if let Some(level) = gzip {
+        let mut output = BufWriter::new(create(&format!("{arg}.gz"))?);
+        hencode_gzip(&mut BufReader::new(input), &mut output, level)?;
+    } else {
+        let mut output = create(&format!("{}.rxc", arg))?;
+        match (model, blocks, lz77, index, threads) {
+            (Some(model), ..) => model.encode(&mut input, &mut output)?,
+            (_, Some(size), ..) => hencode_blocks(&mut BufReader::new(input), &mut output, size)?,
+            (_, _, Some(level), ..) => {
+                hencode_lz77(&mut BufReader::new(input), &mut output, level)?
+            }
+            _ if adaptive => {
+                hencode_adaptive(&mut BufReader::new(input), &mut BufWriter::new(output))?
+            }
+            _ if bwt => hencode_transformed(
+                &mut BufReader::new(input),
+                &mut output,
+                Transforms::ALL,
+                DEFAULT_TRANSFORM_BLOCK_SIZE,
+            )?,
+            _ if interleaved => hencode_interleaved(&mut input, &mut output)?,
+            _ if context => hencode_context_seekable(&mut input, &mut output)?,
+            (_, _, _, _, Some(threads)) => {
+                hencode_chunked(&mut input, &mut output, DEFAULT_CHUNK_SIZE, threads)?
+            }
+            (_, _, _, Some(interval), _) => hencode_indexed(&mut input, &mut output, interval)?,
+            _ => hencode_seekable(&mut input, &mut output)?,
+        }
+    }
+    Ok(())
 }
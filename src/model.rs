@@ -0,0 +1,225 @@
+use std::io::{self, prelude::*};
+
+use crate::table::Table;
+use crate::tree::Tree;
+use crate::{hdecode_payload, hencode_payload, Decoder, Error};
+
+/// Kept short (instead of the banners used for trees) since models are meant for small inputs
+const MODEL_HEADER: &[u8; 4] = b"rxm3";
+/// Starts stored models, followed by the code lengths. Independent of the tree header
+/// version, so it only has to be bumped when the way models are stored changes.
+const MODEL_FILE_HEADER: &[u8; 4] = b"rxf1";
+
+/// Shared model trained on sample data, used to encode many inputs without storing
+/// a tree for each of them.
+///
+/// Every byte is assigned a code, including bytes that never appeared in the samples,
+/// so any input can be encoded. Encoded data starts with the id of the model, decoding
+/// with a different model fails with [`Error::ModelMismatch`].
+pub struct Model {
+    tree: Tree,
+    table: Table,
+    id: u64,
+}
+
+impl Model {
+    /// Trains a model on the bytes of all `samples`
+    ///
+    /// The byte counts are smoothed by adding 1 to every byte, so bytes that are not
+    /// contained in the samples still get a (long) code.
+    pub fn train<R: Read>(samples: impl IntoIterator<Item = R>) -> Result<Model, Error> {
+        let mut counts = [1usize; 256];
+        let mut buf = [0u8; 8 * 1024];
+        for mut sample in samples {
+            loop {
+                let read = match sample.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(read) => read,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err.into()),
+                };
+                for &byte in &buf[..read] {
+                    counts[byte as usize] += 1;
+                }
+            }
+        }
+        let freq = counts
+            .iter()
+            .enumerate()
+            .map(|(byte, &count)| Tree::Leaf(byte as u8, count))
+            .collect();
+        Ok(Model::from_tree(Tree::mktree(freq)))
+    }
+
+    fn from_tree(tree: Tree) -> Model {
+        let lengths = tree.code_lengths();
+        let table = Table::from_lengths(&lengths).expect("trees always describe a valid code");
+        Model {
+            id: fnv1a(&lengths),
+            tree,
+            table,
+        }
+    }
+
+    /// The id identifying this model, derived from its code
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn tree(&self) -> &Tree {
+        &self.tree
+    }
+
+    pub fn table(&self) -> &Table {
+        &self.table
+    }
+
+    /// Stores the model as the code lengths of its tree, see [`Tree::store_lengths`]
+    pub fn store(&self, output: &mut impl Write) -> Result<(), io::Error> {
+        output.write_all(MODEL_FILE_HEADER)?;
+        self.tree.store_lengths(output)
+    }
+
+    /// Loads a model stored by [`Model::store`]
+    ///
+    /// # Errors
+    /// This method returns an error if `input` does not contain a model stored by this
+    /// version
+    pub fn load(input: &mut impl Read) -> Result<Model, io::Error> {
+        let mut header = [0u8; MODEL_FILE_HEADER.len()];
+        input.read_exact(&mut header)?;
+        if &header != MODEL_FILE_HEADER {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file is not a model of this version, train the model again",
+            ));
+        }
+        Ok(Model::from_tree(Tree::from_lengths(
+            &Tree::<u8>::load_lengths(input)?,
+        )?))
+    }
+
+    /// Encodes `input` into `output` using this model
+    pub fn encode(&self, input: &mut impl Read, output: &mut impl Write) -> Result<(), Error> {
+        output.write_all(MODEL_HEADER)?;
+        output.write_all(&self.id.to_be_bytes())?;
+        hencode_payload(&self.tree, input, output)
+    }
+
+    /// Decodes `input` that was encoded with this model into `output`
+    ///
+    /// # Errors
+    /// This method returns [`Error::ModelMismatch`] if `input` was encoded using a
+    /// different model
+    pub fn decode(&self, mut input: impl BufRead, output: impl Write) -> Result<(), Error> {
+        self.check_header(&mut input)?;
+        hdecode_payload(&self.table, input, output)
+    }
+
+    /// Creates a [`Decoder`] for `input` that was encoded with this model
+    ///
+    /// # Errors
+    /// This method returns [`Error::ModelMismatch`] if `input` was encoded using a
+    /// different model
+    pub fn decoder<R: BufRead>(&self, mut input: R) -> Result<Decoder<R, &Table>, Error> {
+        self.check_header(&mut input)?;
+        Decoder::with_table(&self.table, input)
+    }
+
+    fn check_header(&self, input: &mut impl Read) -> Result<(), Error> {
+        let mut header = [0u8; MODEL_HEADER.len()];
+        input.read_exact(&mut header)?;
+        if &header != MODEL_HEADER {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "data was not encoded using a model",
+            )
+            .into());
+        }
+        let mut id = [0u8; 8];
+        input.read_exact(&mut id)?;
+        let found = u64::from_be_bytes(id);
+        if found != self.id {
+            return Err(Error::ModelMismatch {
+                expected: self.id,
+                found,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// 64bit FNV-1a hash
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES: [&str; 3] = [
+        r#"{"id": 1, "name": "alpha", "tags": ["a", "b"]}"#,
+        r#"{"id": 2, "name": "beta", "tags": []}"#,
+        r#"{"id": 3, "name": "gamma", "tags": ["c"]}"#,
+    ];
+
+    fn model() -> Model {
+        Model::train(SAMPLES.iter().map(|sample| sample.as_bytes())).expect("io err")
+    }
+
+    #[test]
+    fn roundtrip() {
+        let model = model();
+        // contains bytes that never appeared in the samples
+        let record = r#"{"id": 4, "name": "DELTA", "tags": ["x", "y", "z"]}"#;
+        let mut coded = Vec::new();
+        model
+            .encode(&mut record.as_bytes(), &mut coded)
+            .expect("encoding failed");
+        let mut out = Vec::new();
+        model.decode(&coded[..], &mut out).expect("decoding failed");
+        assert_eq!(record.as_bytes(), &out);
+
+        let mut out = String::new();
+        model
+            .decoder(&coded[..])
+            .expect("decoding failed")
+            .read_to_string(&mut out)
+            .expect("decoding failed");
+        assert_eq!(record, out);
+    }
+
+    #[test]
+    fn store_load() {
+        let model = model();
+        let mut stored = Vec::new();
+        model.store(&mut stored).expect("io err");
+        let loaded = Model::load(&mut &stored[..]).expect("invalid model");
+        assert_eq!(model.id(), loaded.id());
+
+        // models used to be stored with the tree header
+        let mut stored = Vec::new();
+        model.tree().store(&mut stored).expect("io err");
+        let Err(err) = Model::load(&mut &stored[..]) else {
+            panic!("model of an older version was loaded");
+        };
+        assert!(err.to_string().contains("version"));
+    }
+
+    #[test]
+    fn mismatch() {
+        let model = model();
+        let other = Model::train([&b"entirely different samples"[..]]).expect("io err");
+        assert_ne!(model.id(), other.id());
+
+        let mut coded = Vec::new();
+        model
+            .encode(&mut &b"{}"[..], &mut coded)
+            .expect("encoding failed");
+        let result = other.decode(&coded[..], Vec::new());
+        assert!(matches!(result, Err(Error::ModelMismatch { .. })));
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::io::{self, prelude::*};
+
+use crate::bitutils::{read_varint, write_varint, Symbol};
+use crate::checksum::{write_trailer, Crc32};
+use crate::header::{self, Mode, HEADER_END};
+use crate::table::Table;
+use crate::tree::{Key, Tree};
+use crate::{Decoder, Error, FLUSH_THRESHOLD};
+
+/// Encodes `keys` into `output`, for alphabets larger than bytes such as
+/// 16bit samples or token ids.
+///
+/// The format matches [`hencode`](crate::hencode), except that the width of the keys
+/// is stored in the header so they cannot be decoded as keys of a different type.
+pub fn hencode_symbols<K: Key>(keys: &[K], output: &mut impl Write) -> Result<(), Error> {
+    let mut counts: HashMap<K, usize> = HashMap::new();
+    let mut crc = Crc32::new();
+    for &key in keys {
+        *counts.entry(key).or_default() += 1;
+        crc.update_key(key);
+    }
+    let freq = counts
+        .iter()
+        .map(|(&key, &count)| Tree::Leaf(key, count))
+        .collect();
+    let tree = Tree::mktree(freq);
+    let bits: usize = tree
+        .sparse_lengths()
+        .iter()
+        .map(|(key, len)| counts.get(key).map_or(0, |&count| count * *len as usize))
+        .sum();
+
+    header::write_mode(output, Mode::Symbols)?;
+    output.write_all(&[K::BITS as u8])?;
+    tree.store_lengths(output)?;
+    write_varint(output, keys.len() as u64)?;
+    write_varint(output, bits.div_ceil(8) as u64)?;
+    output.write_all(HEADER_END)?;
+    encode_keys(&tree, keys, output)?;
+    write_trailer(output, &crc)?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Writes the encoded `keys`, which must all be part of `tree`
+///
+/// Like [`encode_payload`](crate::encode_payload) the number of keys has to be
+/// stored separately.
+pub(crate) fn encode_keys<K: Key>(
+    tree: &Tree<K>,
+    keys: &[K],
+    output: &mut impl Write,
+) -> Result<(), Error> {
+    let map = tree.make_conversion_map();
+    let mut encoded = Symbol {
+        bytes: Vec::with_capacity(FLUSH_THRESHOLD + 1),
+        bitpos: 0,
+        bytepos: 0,
+    };
+    for key in keys {
+        encoded.append_sym(&map[key]);
+        if encoded.bytepos >= FLUSH_THRESHOLD {
+            encoded.write_complete(output)?;
+        }
+    }
+    output.write_all(&encoded.bytes)?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Decodes all keys from `input` that was encoded by [`hencode_symbols`]
+///
+/// # Errors
+/// This function returns an error if `input` is no valid encoded data or was encoded
+/// using keys of a different type
+pub fn hdecode_symbols<K: Key>(mut input: impl BufRead) -> Result<Vec<K>, Error> {
+    if header::read_mode(&mut input)? != Mode::Symbols {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "file does not contain symbols, use hdecode instead",
+        )
+        .into());
+    }
+    after_mode::<K, _>(input)?.collect()
+}
+
+/// Prepares decoding symbol mode data from `input` that is positioned after the mode
+pub(crate) fn after_mode<K: Key, R: BufRead>(
+    mut input: R,
+) -> Result<Decoder<R, Table<K>, K>, Error> {
+    let mut bits = [0u8];
+    input.read_exact(&mut bits)?;
+    if bits[0] as u32 != K::BITS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "file contains {}bit keys, expected {}bit keys",
+                bits[0],
+                K::BITS
+            ),
+        )
+        .into());
+    }
+    let lengths = Tree::<K>::load_sparse_lengths(&mut input)?;
+    let len = read_varint(&mut input)?;
+    let size = read_varint(&mut input)?;
+    header::read_end(&mut input)?;
+    let table = Table::from_sparse_lengths(&lengths)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "code cannot be decoded"))?;
+    Decoder::with_trailer(table, input, len, size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hdecode, hencode};
+
+    #[test]
+    fn samples_u16() {
+        // a slow sine wave, most of the structure is lost when split into bytes
+        let samples: Vec<u16> = (0..50000)
+            .map(|num| (((num as f64 / 100.0).sin() + 1.0) * 700.0) as u16 + 30000)
+            .collect();
+        let mut coded = Vec::new();
+        hencode_symbols(&samples, &mut coded).expect("encoding failed");
+        assert_eq!(
+            samples,
+            hdecode_symbols::<u16>(&coded[..]).expect("decoding failed")
+        );
+
+        let bytes: Vec<u8> = samples
+            .iter()
+            .flat_map(|sample| sample.to_be_bytes())
+            .collect();
+        let mut byte_coded = Vec::new();
+        hencode(&mut &bytes[..], &mut byte_coded).expect("encoding failed");
+        assert!(coded.len() < byte_coded.len());
+    }
+
+    #[test]
+    fn tokens_u32() {
+        let tokens: Vec<u32> = [70000, 3, 70000, 12, 99_999, 3, 3, 70000].repeat(20);
+        let mut coded = Vec::new();
+        hencode_symbols(&tokens, &mut coded).expect("encoding failed");
+        assert_eq!(
+            tokens,
+            hdecode_symbols::<u32>(&coded[..]).expect("decoding failed")
+        );
+
+        // the code lengths take no memory for the unused keys in between
+        let tokens = [u32::MAX, 0, u32::MAX - 7, u32::MAX];
+        let mut coded = Vec::new();
+        hencode_symbols(&tokens, &mut coded).expect("encoding failed");
+        assert_eq!(
+            &tokens[..],
+            hdecode_symbols::<u32>(&coded[..]).expect("decoding failed")
+        );
+
+        let mut coded = Vec::new();
+        hencode_symbols::<u32>(&[], &mut coded).expect("encoding failed");
+        assert!(hdecode_symbols::<u32>(&coded[..])
+            .expect("decoding failed")
+            .is_empty());
+    }
+
+    #[test]
+    fn key_width() {
+        let mut coded = Vec::new();
+        hencode_symbols(&[1u16, 2, 3], &mut coded).expect("encoding failed");
+        assert!(hdecode_symbols::<u32>(&coded[..]).is_err());
+        assert!(hdecode(&coded[..], Vec::new()).is_err());
+
+        // byte keys can be decoded by the byte api
+        let mut coded = Vec::new();
+        hencode_symbols(&b"bytes"[..], &mut coded).expect("encoding failed");
+        let mut out = Vec::new();
+        hdecode(&coded[..], &mut out).expect("decoding failed");
+        assert_eq!(b"bytes", &out[..]);
+    }
+}
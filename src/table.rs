@@ -1,188 +1,242 @@
-use std::{fmt::Display, ops::Index};
+use std::{fmt::Display, io::BufRead, ops::Index};
 
-use crate::{bitutils::Symbol, tree::Tree};
+use crate::{
+    bitutils::Symbol,
+    tree::{canonical_codes, sparse_canonical_codes, Key, Tree},
+    window::BitWindow,
+    Error,
+};
 
+/// number of bits used to index the root table and the maximum for subtables
+const LEVEL_BITS: usize = 8;
+
+/// `bitlen` of [`Entry::Map`] is the number of bits the code takes on the level of the entry
 #[derive(Clone, Copy, Debug)]
-pub enum Entry {
-    Map { byte: u8, bitlen: usize },
+pub enum Entry<K: Key = u8> {
+    Map { key: K, bitlen: usize },
     Subtable { offset: usize, bitdepth: usize },
 }
 
+/// Decode table with a 8bit root table, for keys of type `K` (bytes unless specified otherwise).
+///
+/// Codes longer than 8 bits continue in subtables of up to 8 bits, which can be nested
+/// arbitrarily deep.
 #[derive(Debug)]
-pub struct Table {
-    table: Vec<Entry>,
+pub struct Table<K: Key = u8> {
+    table: Vec<Entry<K>>,
 }
 
 #[derive(Debug)]
-struct Conversion {
-    byte: u8,
+struct Conversion<K> {
+    key: K,
     representation: Symbol,
 }
 
-#[derive(Debug)]
-struct SubtableSize {
-    root_id: u8,
-    bitdepth: usize,
-}
-
-impl Table {
-    fn new() -> Table {
+impl<K: Key> Table<K> {
+    fn new() -> Table<K> {
         Table {
             table: vec![
                 Entry::Subtable {
                     offset: 0,
                     bitdepth: 0
                 };
-                256
+                1 << LEVEL_BITS
             ],
         }
     }
 
     /// # Returns
-    /// [None] if `root` was not of type [Tree::Root]
-    pub fn from_tree_root(root: &Tree) -> Option<Self> {
-        let conversion_map = root.make_conversion_map();
+    /// [None] if the code of `root` cannot be represented by a table
+    pub fn from_tree_root(root: &Tree<K>) -> Option<Self> {
+        Table::from_sparse_lengths(&root.sparse_lengths())
+    }
+
+    /// Builds the table for the canonical code described by `lengths`
+    ///
+    /// # Returns
+    /// [None] if `lengths` do not describe a complete prefix code
+    pub fn from_lengths(lengths: &[u8]) -> Option<Self> {
+        Some(Table::from_codes(canonical_codes(lengths).ok()?))
+    }
+
+    /// Builds the table for the canonical code of the keys in `lengths`, which have to be
+    /// sorted by key
+    ///
+    /// # Returns
+    /// [None] if `lengths` do not describe a complete prefix code
+    pub fn from_sparse_lengths(lengths: &[(K, u8)]) -> Option<Self> {
+        Some(Table::from_codes(sparse_canonical_codes(lengths).ok()?))
+    }
+
+    fn from_codes(codes: Vec<(K, Symbol)>) -> Self {
+        let conversions: Vec<Conversion<K>> = codes
+            .into_iter()
+            .map(|(key, representation)| Conversion {
+                key,
+                representation,
+            })
+            .collect();
 
         let mut table = Table::new();
-        let mut subtables = Vec::new();
-        for (byte, symbol) in conversion_map {
-            let conversion = Conversion {
-                byte,
-                representation: symbol,
-            };
-            match conversion.representation.bytepos {
-                0 => fill_symbol(&mut table, conversion),
-                1 => subtables.push(conversion),
-                _ => {
-                    eprintln!("cannot build huffman table");
-                    return None;
+        let conversions: Vec<&Conversion<K>> = conversions.iter().collect();
+        fill_level(&mut table, 0, LEVEL_BITS, 0, &conversions);
+        table
+    }
+
+    /// Decodes a single key from `window`, consuming the bits of its code
+    ///
+    /// # Errors
+    /// This method returns an error if `window` runs out of bits or fails to load more
+    #[inline(always)]
+    pub fn decode<R: BufRead>(&self, window: &mut BitWindow<R>) -> Result<K, Error> {
+        let mut offset = 0;
+        let mut bits = LEVEL_BITS;
+        loop {
+            match self.table[offset + window.show(bits)] {
+                Entry::Map { key, bitlen } => {
+                    window.consume(bitlen)?;
+                    return Ok(key);
+                }
+                Entry::Subtable {
+                    offset: next,
+                    bitdepth,
+                } => {
+                    window.consume(bits)?;
+                    offset = next;
+                    bits = bitdepth;
                 }
             }
         }
-
-        let subtable_sizes = subtable_sizes(&subtables);
-        for conversion in subtables {
-            assert!(conversion.representation.bytepos == 1);
-            let size = subtable_sizes
-                .iter()
-                .find(|size| size.root_id == conversion.representation.bytes[0])
-                .expect("should always find something");
-            fill_subtable(&mut table, size, conversion);
-        }
-
-        Some(table)
     }
 }
 
-impl Index<usize> for Table {
-    type Output = Entry;
+impl<K: Key> Index<usize> for Table<K> {
+    type Output = Entry<K>;
 
     fn index(&self, index: usize) -> &Self::Output {
         self.table.index(index)
     }
 }
 
-fn fill_subtable(table: &mut Table, subtable: &SubtableSize, conversion: Conversion) {
-    let offset = match table.table[subtable.root_id as usize] {
-        Entry::Subtable {
-            offset: 0,
-            bitdepth: 0,
-        } => {
-            let start = table.table.len();
-            let subtable_len = 1 << subtable.bitdepth;
-            for _ in 0..subtable_len {
-                table.table.push(Entry::Subtable {
-                    offset: 0,
-                    bitdepth: 0,
-                });
-            }
-            table.table[subtable.root_id as usize] = Entry::Subtable {
-                offset: start,
-                bitdepth: subtable.bitdepth,
-            };
-            start
-        }
-        Entry::Subtable {
-            offset: index,
-            bitdepth,
-        } => {
-            assert_eq!(bitdepth, subtable.bitdepth, "cannot happen");
-            index
-        }
-        Entry::Map { byte: _, bitlen: _ } => panic!("there should be a subtable entry"),
-    };
-
-    // local index
-    let index = conversion.representation.bytes[1] as usize >> (8 - subtable.bitdepth);
-    // global index
-    let index = offset + index;
-
-    let inflation = 1 << (subtable.bitdepth - conversion.representation.bitpos);
-    for pos in index..index + inflation {
-        table.table[pos] = Entry::Map {
-            byte: conversion.byte,
-            bitlen: conversion.representation.bitpos,
-        }
-    }
+/// reads `amt` bits of `sym` starting at `start` (msb first)
+fn bits_at(sym: &Symbol, start: usize, amt: usize) -> usize {
+    (start..start + amt).fold(0, |acc, index| (acc << 1) | sym.bit(index) as usize)
 }
 
-/// `conversions` are all conversions that require more then 8 bits to encode
-fn subtable_sizes(conversions: &[Conversion]) -> Vec<SubtableSize> {
-    conversions
-        .iter()
-        .fold(Vec::new(), |mut acc: Vec<SubtableSize>, entry| {
-            match acc
-                .iter_mut()
-                .find(|size| size.root_id == entry.representation.bytes[0])
-            {
-                Some(size) => {
-                    size.bitdepth = size.bitdepth.max(entry.representation.bitpos);
-                    acc
-                }
-                None => {
-                    acc.push(SubtableSize {
-                        root_id: entry.representation.bytes[0],
-                        bitdepth: entry.representation.bitpos,
-                    });
-                    acc
+/// Fills the (sub)table of `bitdepth` bits at `offset`.
+///
+/// All `conversions` share the same first `skip` bits, which were consumed
+/// by the levels above this one.
+fn fill_level<K: Key>(
+    table: &mut Table<K>,
+    offset: usize,
+    bitdepth: usize,
+    skip: usize,
+    conversions: &[&Conversion<K>],
+) {
+    let mut longer = Vec::new();
+    for &conversion in conversions {
+        let remaining = conversion.representation.bits() - skip;
+        if remaining <= bitdepth {
+            let index =
+                bits_at(&conversion.representation, skip, remaining) << (bitdepth - remaining);
+            let inflation = 1 << (bitdepth - remaining);
+            for pos in offset + index..offset + index + inflation {
+                table.table[pos] = Entry::Map {
+                    key: conversion.key,
+                    bitlen: remaining,
                 }
             }
-        })
-}
+        } else {
+            longer.push(conversion);
+        }
+    }
 
-fn fill_symbol(table: &mut Table, conversion: Conversion) {
-    let index = conversion.representation.bytes[0] as usize;
-    let inflation = 1 << (8 - conversion.representation.bitpos);
-    for pos in index..index + inflation {
-        table.table[pos] = Entry::Map {
-            byte: conversion.byte,
-            bitlen: conversion.representation.bitpos,
+    // canonical codes are sorted, so codes sharing an entry are next to each other
+    let mut longer = longer.into_iter().peekable();
+    while let Some(first) = longer.next() {
+        let index = bits_at(&first.representation, skip, bitdepth);
+        let mut group = vec![first];
+        while let Some(next) = longer
+            .next_if(|conversion| bits_at(&conversion.representation, skip, bitdepth) == index)
+        {
+            group.push(next);
         }
+
+        let skip = skip + bitdepth;
+        let depth = group
+            .iter()
+            .map(|conversion| conversion.representation.bits() - skip)
+            .max()
+            .expect("group contains at least one conversion")
+            .min(LEVEL_BITS);
+        let start = table.table.len();
+        table.table.extend((0..1 << depth).map(|_| Entry::Subtable {
+            offset: 0,
+            bitdepth: 0,
+        }));
+        table.table[offset + index] = Entry::Subtable {
+            offset: start,
+            bitdepth: depth,
+        };
+        fill_level(table, start, depth, skip, &group);
     }
 }
 
-impl Display for Table {
+impl<K: Key> Display for Table<K> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for i in 0..256 {
-            let line = self.table[i];
-            match line {
-                Entry::Map { byte, bitlen } => {
-                    writeln!(f, "{i:08b}: byte={byte}, takes {bitlen} bits")?;
-                }
-                Entry::Subtable { offset, bitdepth } => {
-                    let len = 1 << bitdepth;
-                    writeln!(f, "{i:08b}: {bitdepth}bit subtable at {offset}")?;
-                    for j in offset..(offset + len) {
-                        let Entry::Map { byte, bitlen } = self.table[j] else {
-                            panic!("no subtables in subtables")
-                        };
-                        // local index (shifted to most significant bits for visual clarity)
-                        let index = (j - offset) << (8 - bitdepth);
-                        writeln!(f, "\t\t{index:08b}: byte={byte}, takes {bitlen} bits")?;
+        fn show<K: Key>(
+            table: &Table<K>,
+            offset: usize,
+            bitdepth: usize,
+            depth: usize,
+            f: &mut std::fmt::Formatter<'_>,
+        ) -> std::fmt::Result {
+            let indent = "\t".repeat(depth);
+            for i in 0..(1 << bitdepth) {
+                // local index (shifted to most significant bits for visual clarity)
+                let index = i << (LEVEL_BITS - bitdepth);
+                match table.table[offset + i] {
+                    Entry::Map { key, bitlen } => {
+                        writeln!(f, "{indent}{index:08b}: key={key}, takes {bitlen} bits")?;
+                    }
+                    Entry::Subtable {
+                        offset: next,
+                        bitdepth: next_depth,
+                    } => {
+                        writeln!(f, "{indent}{index:08b}: {next_depth}bit subtable at {next}")?;
+                        show(table, next, next_depth, depth + 1, f)?;
                     }
                 }
             }
+            Ok(())
+        }
+        show(self, 0, LEVEL_BITS, 0, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deep_codes() {
+        // lengths 1, 2, ..., 39, 40, 40: codes up to 40 bits need 4 levels of subtables
+        let mut lengths: Vec<u8> = (1..=40).collect();
+        lengths.push(40);
+        let table: Table = Table::from_lengths(&lengths).expect("valid code");
+
+        for (byte, sym) in canonical_codes::<u8>(&lengths).expect("valid code") {
+            let mut data = sym.clone();
+            // follow up with some bits so the window does not run dry
+            data.append_sym(&Symbol {
+                bytes: vec![0xff, 0xff],
+                bitpos: 8,
+                bytepos: 1,
+            });
+            let mut window: BitWindow<&[u8]> = (&data.bytes[..]).into();
+            assert_eq!(byte, table.decode(&mut window).expect("decoding failed"));
+            assert_eq!(0xff, window.show(8), "consumed the wrong number of bits");
         }
-        Ok(())
     }
 }
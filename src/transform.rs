@@ -0,0 +1,532 @@
+use std::collections::HashMap;
+use std::io::{self, prelude::*};
+
+use crate::bitutils::{read_varint, write_varint};
+use crate::checksum::{read_trailer, write_trailer, Crc32};
+use crate::header::{self, Mode, HEADER_END};
+use crate::symbols::encode_keys;
+use crate::table::Table;
+use crate::tree::Tree;
+use crate::{Decoder, Error};
+
+/// Block size used by [`hencode_transformed`] if none is given, larger blocks compress
+/// better but take longer to sort
+pub const DEFAULT_TRANSFORM_BLOCK_SIZE: usize = 256 * 1024;
+
+/// Largest block size accepted by [`hencode_transformed`] and [`TransformDecoder`],
+/// sorting the rotations of a block takes several times its size in memory
+pub const MAX_TRANSFORM_BLOCK_SIZE: usize = 16 * 1024 * 1024;
+
+/// first digit of a zero run, see [`rle_encode`]
+const RUNA: u16 = 0;
+/// second digit of a zero run, see [`rle_encode`]
+const RUNB: u16 = 1;
+
+/// Transforms applied to each block before it is huffman coded, in this order
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Transforms {
+    /// Burrows-Wheeler transform, groups bytes that appear in similar contexts
+    pub bwt: bool,
+    /// move-to-front, turns repeated bytes into zeros
+    pub mtf: bool,
+    /// zero-run length encoding, replaces runs of zeros by their length
+    pub rle: bool,
+}
+
+impl Transforms {
+    /// all transforms, as in bzip2
+    pub const ALL: Transforms = Transforms {
+        bwt: true,
+        mtf: true,
+        rle: true,
+    };
+
+    fn to_byte(self) -> u8 {
+        self.bwt as u8 | (self.mtf as u8) << 1 | (self.rle as u8) << 2
+    }
+
+    fn from_byte(byte: u8) -> Result<Transforms, io::Error> {
+        if byte >> 3 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unknown transform",
+            ));
+        }
+        Ok(Transforms {
+            bwt: byte & 1 != 0,
+            mtf: byte & 2 != 0,
+            rle: byte & 4 != 0,
+        })
+    }
+}
+
+/// Encodes `input` into `output` in blocks of `block_size` bytes, applying `transforms`
+/// to each block before huffman coding it.
+///
+/// The applied transforms and the block size are recorded in the header,
+/// [`hdecode`](crate::hdecode) reverses the transforms automatically. Each block is
+/// stored as its length, the primary index of the Burrows-Wheeler transform (if
+/// applied), the code lengths of its tree, the number of keys, the length of the
+/// payload and the payload itself. A block length of 0 marks the end.
+///
+/// # Panics
+/// This function panics if `block_size` is 0 or larger than [`MAX_TRANSFORM_BLOCK_SIZE`]
+pub fn hencode_transformed(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    transforms: Transforms,
+    block_size: usize,
+) -> Result<(), Error> {
+    assert!(block_size > 0, "blocks must not be empty");
+    assert!(
+        block_size <= MAX_TRANSFORM_BLOCK_SIZE,
+        "blocks must not be larger than {MAX_TRANSFORM_BLOCK_SIZE} bytes"
+    );
+    header::write_mode(output, Mode::Transform)?;
+    output.write_all(&[transforms.to_byte()])?;
+    write_varint(output, block_size as u64)?;
+    output.write_all(HEADER_END)?;
+
+    let mut block = Vec::with_capacity(block_size);
+    let mut payload = Vec::new();
+    let mut crc = Crc32::new();
+    loop {
+        block.clear();
+        input
+            .by_ref()
+            .take(block_size as u64)
+            .read_to_end(&mut block)?;
+        if block.is_empty() {
+            break;
+        }
+        write_varint(output, block.len() as u64)?;
+        crc.update(&block);
+
+        let mut data = block.clone();
+        if transforms.bwt {
+            let primary;
+            (data, primary) = bwt_encode(&data);
+            write_varint(output, primary as u64)?;
+        }
+        if transforms.mtf {
+            mtf_encode(&mut data);
+        }
+        let keys = if transforms.rle {
+            rle_encode(&data)
+        } else {
+            data.iter().map(|&byte| byte as u16).collect()
+        };
+
+        let mut counts: HashMap<u16, usize> = HashMap::new();
+        for &key in keys.iter() {
+            *counts.entry(key).or_default() += 1;
+        }
+        let freq = counts
+            .iter()
+            .map(|(&key, &count)| Tree::Leaf(key, count))
+            .collect();
+        let tree = Tree::mktree(freq);
+        tree.store_lengths(output)?;
+        payload.clear();
+        encode_keys(&tree, &keys, &mut payload)?;
+        write_varint(output, keys.len() as u64)?;
+        write_varint(output, payload.len() as u64)?;
+        output.write_all(&payload)?;
+    }
+    write_varint(output, 0)?;
+    write_trailer(output, &crc)?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Pull based decoder for data encoded by [`hencode_transformed`].
+///
+/// Decodes one block at a time, so memory usage is bounded by the block size.
+pub struct TransformDecoder<R: BufRead> {
+    input: R,
+    transforms: Transforms,
+    /// largest length of a block, stored in the header
+    block_size: usize,
+    /// the decoded current block and the number of bytes already returned from it
+    block: Vec<u8>,
+    position: usize,
+    done: bool,
+    crc: Crc32,
+}
+
+impl<R: BufRead> TransformDecoder<R> {
+    /// Reads the header from `input` and prepares decoding the blocks
+    ///
+    /// # Errors
+    /// This method returns an error if `input` does not start with a valid transform mode header
+    pub fn new(mut input: R) -> Result<Self, Error> {
+        if header::read_mode(&mut input)? != Mode::Transform {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file was not transformed, use hdecode instead",
+            )
+            .into());
+        }
+        TransformDecoder::after_mode(input)
+    }
+
+    /// Prepares decoding transform mode data from `input` that is positioned after the mode
+    pub(crate) fn after_mode(mut input: R) -> Result<Self, Error> {
+        let mut transforms = [0u8];
+        input.read_exact(&mut transforms)?;
+        let transforms = Transforms::from_byte(transforms[0])?;
+        let block_size = usize::try_from(read_varint(&mut input)?)
+            .ok()
+            .filter(|size| (1..=MAX_TRANSFORM_BLOCK_SIZE).contains(size))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid block size"))?;
+        header::read_end(&mut input)?;
+        Ok(TransformDecoder {
+            input,
+            transforms,
+            block_size,
+            block: Vec::new(),
+            position: 0,
+            done: false,
+            crc: Crc32::new(),
+        })
+    }
+
+    /// The transforms that were applied to the data
+    pub fn transforms(&self) -> Transforms {
+        self.transforms
+    }
+
+    /// Decodes the next block and reverses the transforms
+    fn next_block(&mut self) -> Result<(), Error> {
+        let len = read_varint(&mut self.input)?;
+        if len == 0 {
+            self.done = true;
+            return self.crc.verify(read_trailer(&mut self.input)?);
+        }
+        if len > self.block_size as u64 {
+            return Err(invalid_block().into());
+        }
+        let len = len as usize;
+        let primary = match self.transforms.bwt {
+            true => Some(read_varint(&mut self.input)? as usize),
+            false => None,
+        };
+        let lengths = Tree::<u16>::load_lengths(&mut self.input)?;
+        let table: Table<u16> = Table::from_lengths(&lengths)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "code cannot be decoded"))?;
+        let keys = read_varint(&mut self.input)?;
+        // zero runs take fewer keys than bytes
+        if keys > len as u64 {
+            return Err(invalid_block().into());
+        }
+        let payload = read_varint(&mut self.input)?;
+        let mut payload = (&mut self.input).take(payload);
+        let keys =
+            Decoder::with_len(table, &mut payload, keys)?.collect::<Result<Vec<u16>, _>>()?;
+        if payload.limit() > 0 {
+            return Err(invalid_block().into());
+        }
+
+        let mut data = if self.transforms.rle {
+            rle_decode(&keys, len)?
+        } else {
+            keys.iter()
+                .map(|&key| u8::try_from(key).map_err(|_| invalid_block()))
+                .collect::<Result<_, _>>()?
+        };
+        if data.len() != len {
+            return Err(invalid_block().into());
+        }
+        if self.transforms.mtf {
+            mtf_decode(&mut data);
+        }
+        if let Some(primary) = primary {
+            if primary >= len {
+                return Err(invalid_block().into());
+            }
+            data = bwt_decode(&data, primary);
+        }
+        self.crc.update(&data);
+        self.block = data;
+        self.position = 0;
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for TransformDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.position == self.block.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.next_block()?;
+        }
+        let read = (self.block.len() - self.position).min(buf.len());
+        buf[..read].copy_from_slice(&self.block[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+fn invalid_block() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "transformed block is broken")
+}
+
+/// Sorts all rotations of `block` by prefix doubling, using counting sort in every
+/// round so sorting takes `O(n log n)` for blocks of `n` bytes
+///
+/// # Returns
+/// The start of every rotation in sorted order
+fn sort_rotations(block: &[u8]) -> Vec<usize> {
+    let len = block.len();
+    let mut order: Vec<usize> = (0..len).collect();
+    order.sort_by_key(|&start| block[start]);
+    // rotations sharing a class are equal in the bytes sorted so far
+    let mut class = vec![0; len];
+    for pair in order.windows(2) {
+        class[pair[1]] = class[pair[0]] + (block[pair[0]] != block[pair[1]]) as usize;
+    }
+    let mut classes = class[order[len - 1]] + 1;
+
+    let mut shifted = vec![0; len];
+    let mut next = vec![0; len];
+    let mut width = 1;
+    while width < len && classes < len {
+        // sorted by the second half already, stable counting sort by the first half
+        for (shift, &start) in shifted.iter_mut().zip(order.iter()) {
+            *shift = (start + len - width) % len;
+        }
+        let mut starts = vec![0; classes + 1];
+        for &start in shifted.iter() {
+            starts[class[start] + 1] += 1;
+        }
+        for num in 1..starts.len() {
+            starts[num] += starts[num - 1];
+        }
+        for &start in shifted.iter() {
+            order[starts[class[start]]] = start;
+            starts[class[start]] += 1;
+        }
+
+        let key = |start: usize| (class[start], class[(start + width) % len]);
+        next[order[0]] = 0;
+        for pair in order.windows(2) {
+            next[pair[1]] = next[pair[0]] + (key(pair[0]) != key(pair[1])) as usize;
+        }
+        std::mem::swap(&mut class, &mut next);
+        classes = class[order[len - 1]] + 1;
+        width *= 2;
+    }
+    order
+}
+
+/// Burrows-Wheeler transform of `block`
+///
+/// # Returns
+/// The last column of the sorted rotations and the row of the original block
+fn bwt_encode(block: &[u8]) -> (Vec<u8>, usize) {
+    let len = block.len();
+    let order = sort_rotations(block);
+    let last = order
+        .iter()
+        .map(|&start| block[(start + len - 1) % len])
+        .collect();
+    let primary = order
+        .iter()
+        .position(|&start| start == 0)
+        .expect("every rotation is sorted");
+    (last, primary)
+}
+
+/// Reverses [`bwt_encode`] by following the last-to-first mapping from `primary`
+fn bwt_decode(last: &[u8], primary: usize) -> Vec<u8> {
+    let mut starts = [0usize; 256];
+    for &byte in last {
+        starts[byte as usize] += 1;
+    }
+    let mut sum = 0;
+    for start in starts.iter_mut() {
+        (*start, sum) = (sum, sum + *start);
+    }
+    let mut seen = [0usize; 256];
+    let first: Vec<usize> = last
+        .iter()
+        .map(|&byte| {
+            seen[byte as usize] += 1;
+            starts[byte as usize] + seen[byte as usize] - 1
+        })
+        .collect();
+
+    let mut block = vec![0; last.len()];
+    let mut row = primary;
+    for byte in block.iter_mut().rev() {
+        *byte = last[row];
+        row = first[row];
+    }
+    block
+}
+
+fn mtf_encode(data: &mut [u8]) {
+    let mut list: Vec<u8> = (0..=u8::MAX).collect();
+    for byte in data.iter_mut() {
+        let index = list
+            .iter()
+            .position(|entry| entry == byte)
+            .expect("every byte is in the list");
+        list[..=index].rotate_right(1);
+        *byte = index as u8;
+    }
+}
+
+fn mtf_decode(data: &mut [u8]) {
+    let mut list: Vec<u8> = (0..=u8::MAX).collect();
+    for byte in data.iter_mut() {
+        let index = *byte as usize;
+        list[..=index].rotate_right(1);
+        *byte = list[0];
+    }
+}
+
+/// Replaces runs of zeros by their length written in bijective base 2 with the digits
+/// [`RUNA`] (1) and [`RUNB`] (2), least significant digit first. All other bytes are
+/// shifted up by one.
+fn rle_encode(data: &[u8]) -> Vec<u16> {
+    let mut keys = Vec::with_capacity(data.len());
+    let mut run = 0usize;
+    for &byte in data.iter().chain([1].iter()) {
+        if byte == 0 {
+            run += 1;
+            continue;
+        }
+        while run > 0 {
+            if run % 2 == 1 {
+                keys.push(RUNA);
+                run = (run - 1) / 2;
+            } else {
+                keys.push(RUNB);
+                run = (run - 2) / 2;
+            }
+        }
+        keys.push(byte as u16 + 1);
+    }
+    // the byte chained to flush the last run
+    keys.pop();
+    keys
+}
+
+/// Reverses [`rle_encode`], failing if the result would be longer than `max_len`
+fn rle_decode(keys: &[u16], max_len: usize) -> Result<Vec<u8>, io::Error> {
+    let mut data = Vec::with_capacity(max_len);
+    let mut run = 0usize;
+    let mut weight = 1usize;
+    for &key in keys.iter().chain([2].iter()) {
+        if key == RUNA || key == RUNB {
+            run = (key as usize + 1)
+                .checked_mul(weight)
+                .and_then(|digit| run.checked_add(digit))
+                .ok_or_else(invalid_block)?;
+            weight = weight.checked_mul(2).ok_or_else(invalid_block)?;
+            continue;
+        }
+        if data.len() + run > max_len {
+            return Err(invalid_block());
+        }
+        data.resize(data.len() + run, 0);
+        (run, weight) = (0, 1);
+        data.push(u8::try_from(key - 1).map_err(|_| invalid_block())?);
+    }
+    data.pop();
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hdecode, hencode};
+
+    fn roundtrip(raw: &[u8], transforms: Transforms, block_size: usize) -> Vec<u8> {
+        let mut coded = Vec::new();
+        hencode_transformed(&mut &raw[..], &mut coded, transforms, block_size)
+            .expect("encoding failed");
+        let mut out = Vec::new();
+        hdecode(&coded[..], &mut out).expect("decoding failed");
+        assert_eq!(raw, out);
+        coded
+    }
+
+    #[test]
+    fn bwt_banana() {
+        let (last, primary) = bwt_encode(b"banana");
+        assert_eq!((b"nnbaaa".to_vec(), 3), (last.clone(), primary));
+        assert_eq!(b"banana".to_vec(), bwt_decode(&last, primary));
+        // periodic blocks have identical rotations
+        let (last, primary) = bwt_encode(b"abababab");
+        assert_eq!(b"abababab".to_vec(), bwt_decode(&last, primary));
+    }
+
+    #[test]
+    fn stages() {
+        let mut data = b"aaabbbaaa\x00\x00".to_vec();
+        mtf_encode(&mut data);
+        assert_eq!(vec![97, 0, 0, 98, 0, 0, 1, 0, 0, 2, 0], data);
+        let keys = rle_encode(&data);
+        mtf_decode(&mut data);
+        assert_eq!(b"aaabbbaaa\x00\x00".to_vec(), data);
+
+        for run in 0..40 {
+            let data: Vec<u8> = [vec![5], vec![0; run], vec![7]].concat();
+            assert_eq!(data, rle_decode(&rle_encode(&data), data.len()).unwrap());
+        }
+        assert_eq!(vec![98, RUNB, 99, RUNB, 2, RUNB, 3, RUNA], keys);
+    }
+
+    #[test]
+    fn logs() {
+        let raw: Vec<u8> = (0..400)
+            .flat_map(|num| {
+                format!(
+                    "2023-11-{:02} INFO request {} served in {}ms\n",
+                    num % 30,
+                    num * 7,
+                    num % 13
+                )
+                .into_bytes()
+            })
+            .collect();
+        let coded = roundtrip(&raw, Transforms::ALL, DEFAULT_TRANSFORM_BLOCK_SIZE);
+        let mut plain = Vec::new();
+        hencode(&mut &raw[..], &mut plain).expect("encoding failed");
+        assert!(coded.len() < plain.len() * 3 / 4);
+    }
+
+    #[test]
+    fn combinations() {
+        let raw = b"the transforms can be combined freely, ".repeat(30);
+        for byte in 0..8 {
+            let transforms = Transforms::from_byte(byte).expect("valid transforms");
+            roundtrip(&raw, transforms, 100);
+            roundtrip(&[], transforms, 100);
+            roundtrip(&[0; 300], transforms, 64);
+        }
+    }
+
+    #[test]
+    fn oversized_block() {
+        let header = |block_size: u64, len: u64| {
+            let mut coded = Vec::new();
+            header::write_mode(&mut coded, Mode::Transform).expect("io err");
+            coded.push(Transforms::ALL.to_byte());
+            write_varint(&mut coded, block_size).expect("io err");
+            coded.extend(HEADER_END);
+            write_varint(&mut coded, len).expect("io err");
+            coded
+        };
+        // the length is checked before anything is allocated for the block
+        assert!(hdecode(&header(100, 1 << 40)[..], Vec::new()).is_err());
+        assert!(hdecode(&header(1 << 40, 1 << 40)[..], Vec::new()).is_err());
+        assert!(hdecode(&header(0, 1)[..], Vec::new()).is_err());
+    }
+}
@@ -1,157 +1,567 @@
-use crate::bitutils::Symbol;
-use std::collections::HashMap;
-use std::fmt::Display;
+use crate::bitutils::{read_varint, write_varint, Symbol};
+use crate::header::{self, HEADER_END, HEADER_START};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fmt::{Debug, Display};
+use std::hash::Hash;
 use std::io::{self, prelude::*};
 
-const HEADER_START: &[u8; 30] = b"----- rxh tree start V2 -----\n";
-const HEADER_END: &[u8; 29] = b"\n----- rxh tree end V2 -----\n";
-const INVALID_VERSION: &str = r#"file does not contain a valid rxh tree start signature.
-If the file contains a valid signature from a prior version you may find a version of this program thats compatible with this file here: https://github.com/devensiv/huffman-coding"#;
+/// Default maximum code length used by [`Tree::mktree`], chosen so that decoding a code
+/// takes at most two lookups in a [`Table`](crate::table::Table)
+pub const MAX_CODE_LEN: u8 = 16;
 
-pub enum Tree {
-    Root(Box<Tree>, Box<Tree>),
-    Leaf(u8, usize),
-    Node(Box<Tree>, Box<Tree>, usize),
+/// Symbol types a [`Tree`] and a [`Table`](crate::table::Table) can be built for.
+///
+/// Dense code lengths are indexed by [`Key::index`], so the memory needed for them grows
+/// with the largest key in use. The sparse variants like [`Tree::sparse_lengths`] only
+/// store the keys in use and should be preferred for large alphabets.
+pub trait Key: Copy + Ord + Hash + Debug + Display {
+    /// number of bits of the type, stored in headers to detect mismatching alphabets
+    const BITS: u32;
+    /// the largest index a key can have
+    const MAX_INDEX: usize;
+
+    fn index(self) -> usize;
+
+    /// # Panics
+    /// This function panics if `index` is larger than [`Key::MAX_INDEX`]
+    fn from_index(index: usize) -> Self;
 }
 
-impl Tree {
-    fn fill_conversion_map(node: &Tree, mut sym: Symbol, map: &mut HashMap<u8, Symbol>) {
-        match node {
-            Tree::Root(left, right) | Tree::Node(left, right, _) => {
-                let mut lsym = sym.clone();
-                lsym.append_bit(false);
-                Tree::fill_conversion_map(left, lsym, map);
-                sym.append_bit(true);
-                Tree::fill_conversion_map(right, sym, map);
+macro_rules! impl_key {
+    ($($ty:ty),*) => {
+        $(
+            impl Key for $ty {
+                const BITS: u32 = <$ty>::BITS;
+                const MAX_INDEX: usize = <$ty>::MAX as usize;
+
+                fn index(self) -> usize {
+                    self as usize
+                }
+
+                fn from_index(index: usize) -> Self {
+                    <$ty>::try_from(index).expect("index out of range for the alphabet")
+                }
             }
-            Tree::Leaf(key, _) => {
-                map.insert(*key, sym);
+        )*
+    };
+}
+
+impl_key!(u8, u16, u32);
+
+/// Huffman tree over keys of type `K`, bytes unless specified otherwise
+pub enum Tree<K: Key = u8> {
+    Root(Box<Tree<K>>, Box<Tree<K>>),
+    Leaf(K, usize),
+    Node(Box<Tree<K>>, Box<Tree<K>>, usize),
+}
+
+impl<K: Key> Tree<K> {
+    /// creates the encoding map from keys to canonical huffman symbols for the leaves under `self`
+    pub fn make_conversion_map(&self) -> HashMap<K, Symbol> {
+        sparse_canonical_codes(&self.sparse_lengths())
+            .expect("lengths of a tree always describe a valid code")
+            .into_iter()
+            .collect()
+    }
+
+    /// Returns the code length of every key, indexed by [`Key::index`].
+    ///
+    /// Keys that are not part of the tree have a length of 0, trailing zeros are omitted.
+    /// One length is allocated per key up to the largest key of the tree, use
+    /// [`Tree::sparse_lengths`] for large alphabets.
+    pub fn code_lengths(&self) -> Vec<u8> {
+        let lengths = self.sparse_lengths();
+        let mut dense = vec![0; lengths.last().map_or(0, |&(key, _)| key.index() + 1)];
+        for (key, len) in lengths {
+            dense[key.index()] = len;
+        }
+        dense
+    }
+
+    /// Returns the keys of the tree with their code length, sorted by key
+    pub fn sparse_lengths(&self) -> Vec<(K, u8)> {
+        fn fill<K: Key>(node: &Tree<K>, depth: u8, lengths: &mut Vec<(K, u8)>) {
+            match node {
+                Tree::Root(left, right) | Tree::Node(left, right, _) => {
+                    fill(left, depth + 1, lengths);
+                    fill(right, depth + 1, lengths);
+                }
+                Tree::Leaf(key, _) => lengths.push((*key, depth)),
             }
         }
+        let mut lengths = Vec::new();
+        fill(self, 0, &mut lengths);
+        lengths.sort_unstable();
+        lengths
     }
 
-    /// creates the encoding map from bytes to huffman symbols from the tree contained under `self`
-    pub fn make_conversion_map(&self) -> HashMap<u8, Symbol> {
-        let mut map = HashMap::new();
-        Tree::fill_conversion_map(
-            self,
-            Symbol {
-                bytes: Vec::new(),
-                bitpos: 0,
-                bytepos: 0,
-            },
-            &mut map,
-        );
-        map
+    /// Builds the canonical tree for the given code lengths
+    ///
+    /// # Errors
+    /// This method returns an error if `lengths` do not describe a complete prefix code
+    pub fn from_lengths(lengths: &[u8]) -> Result<Tree<K>, io::Error> {
+        Tree::from_sparse_lengths(&to_sparse(lengths)?)
     }
 
-    pub fn store(&self, file: &mut impl Write) -> Result<(), io::Error> {
-        match self {
-            Tree::Leaf(key, _) => {
-                file.write_all(&[1, *key])?;
+    /// Builds the canonical tree for the code lengths of the keys in `lengths`, which
+    /// have to be sorted by key
+    ///
+    /// # Errors
+    /// This method returns an error if `lengths` do not describe a complete prefix code
+    pub fn from_sparse_lengths(lengths: &[(K, u8)]) -> Result<Tree<K>, io::Error> {
+        fn build<K: Key>(codes: &[(K, Symbol)], depth: usize) -> Result<Tree<K>, io::Error> {
+            if let [(key, sym)] = codes {
+                if sym.bits() == depth {
+                    return Ok(Tree::Leaf(*key, 0));
+                }
             }
-            Tree::Node(left, right, _) => {
-                assert_eq!(file.write(&[0])?, 1);
-                left.store(file)?;
-                right.store(file)?;
+            // canonical codes are sorted, so all codes starting with 0 come first
+            let split = codes
+                .iter()
+                .position(|(_, sym)| sym.bits() > depth && sym.bit(depth))
+                .unwrap_or(codes.len());
+            let (left, right) = codes.split_at(split);
+            if left.is_empty() || right.is_empty() {
+                return Err(invalid_code());
             }
-            Tree::Root(left, right) => {
-                file.write_all(HEADER_START)?;
-                assert_eq!(file.write(&[255])?, 1);
-                left.store(file)?;
-                right.store(file)?;
-                file.write_all(HEADER_END)?;
+            let left = Box::new(build(left, depth + 1)?);
+            let right = Box::new(build(right, depth + 1)?);
+            if depth == 0 {
+                Ok(Tree::Root(left, right))
+            } else {
+                Ok(Tree::Node(left, right, 0))
             }
         }
+        build(&sparse_canonical_codes(lengths)?, 0)
+    }
+
+    /// Stores the code lengths of the tree under `self`, enclosed in the header signatures
+    pub fn store(&self, file: &mut impl Write) -> Result<(), io::Error> {
+        file.write_all(HEADER_START)?;
+        self.store_lengths(file)?;
+        file.write_all(HEADER_END)?;
         Ok(())
     }
 
-    pub fn try_load(input: &mut impl Read) -> Result<Tree, io::Error> {
-        let mut buffer = [0u8; HEADER_START.len()]; //header start is 29 bytes
-        input.read_exact(&mut buffer)?;
-        if &buffer != HEADER_START {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, INVALID_VERSION));
+    /// Stores only the code lengths of the tree under `self`
+    ///
+    /// The number of lengths is written as varint, followed by one byte per length.
+    /// A length of zero is followed by a varint counting the additional zeros after it.
+    pub fn store_lengths(&self, file: &mut impl Write) -> Result<(), io::Error> {
+        let lengths = self.sparse_lengths();
+        write_varint(
+            file,
+            lengths.last().map_or(0, |&(key, _)| key.index() as u64 + 1),
+        )?;
+        let mut next = 0;
+        for (key, len) in lengths {
+            // keys between the previous and this one are not part of the tree
+            if key.index() > next {
+                file.write_all(&[0])?;
+                write_varint(file, (key.index() - next - 1) as u64)?;
+            }
+            file.write_all(&[len])?;
+            next = key.index() + 1;
         }
+        Ok(())
+    }
 
-        let result = Tree::load(input);
+    /// Loads a tree stored by [`Tree::store`]
+    ///
+    /// Use [`Tree::try_load_lengths`] to avoid building the tree if only the code is required
+    pub fn try_load(input: &mut impl Read) -> Result<Tree<K>, io::Error> {
+        Tree::from_lengths(&Self::try_load_lengths(input)?)
+    }
+
+    /// Loads the code lengths stored by [`Tree::store`]
+    pub fn try_load_lengths(input: &mut impl Read) -> Result<Vec<u8>, io::Error> {
+        header::read_start(input)?;
+        let lengths = Self::load_lengths(input)?;
+        header::read_end(input)?;
+        Ok(lengths)
+    }
 
-        let mut buffer = [0u8; HEADER_END.len()]; //header end is 28 bytes
-        input.read_exact(&mut buffer)?;
-        if &buffer != HEADER_END {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, INVALID_VERSION));
+    /// Loads the code lengths stored by [`Tree::store_lengths`], indexed by [`Key::index`]
+    ///
+    /// The stored count is not trusted for allocations, but one length is allocated per
+    /// key up to the largest stored one. Use [`Tree::load_sparse_lengths`] for large
+    /// alphabets.
+    pub fn load_lengths(input: &mut impl Read) -> Result<Vec<u8>, io::Error> {
+        let lengths = Self::load_sparse_lengths(input)?;
+        let mut dense = vec![0; lengths.last().map_or(0, |&(key, _)| key.index() + 1)];
+        for (key, len) in lengths {
+            dense[key.index()] = len;
         }
-        result
+        Ok(dense)
     }
 
-    fn load(input: &mut impl Read) -> Result<Tree, io::Error> {
-        let mut buffer = [0u8];
-        input.read_exact(&mut buffer)?;
-        match buffer[0] {
-            0 => Ok(Tree::Node(
-                Box::new(Tree::load(input)?),
-                Box::new(Tree::load(input)?),
-                0,
-            )),
-            1 => {
-                let mut buffer = [0u8];
-                input.read_exact(&mut buffer)?;
-                Ok(Tree::Leaf(buffer[0], 0))
+    /// Loads the code lengths stored by [`Tree::store_lengths`] of the keys with a
+    /// length other than 0, sorted by key
+    ///
+    /// Only the stored lengths are allocated, runs of keys without code take no memory.
+    pub fn load_sparse_lengths(input: &mut impl Read) -> Result<Vec<(K, u8)>, io::Error> {
+        let count = read_varint(input)?;
+        if count > K::MAX_INDEX as u64 + 1 {
+            return Err(invalid_format());
+        }
+        let mut lengths = Vec::new();
+        let mut next = 0;
+        while next < count {
+            let mut len = [0u8];
+            input.read_exact(&mut len)?;
+            if len[0] == 0 {
+                let run = read_varint(input)?;
+                next = next
+                    .checked_add(run)
+                    .and_then(|next| next.checked_add(1))
+                    .filter(|&next| next <= count)
+                    .ok_or_else(invalid_format)?;
+            } else {
+                lengths.push((K::from_index(next as usize), len[0]));
+                next += 1;
+            }
+        }
+        Ok(lengths)
+    }
+
+    /// Builds an optimal tree from the leaves in `freq` whose codes are at most
+    /// [`MAX_CODE_LEN`] bits long.
+    ///
+    /// The limit is raised for alphabets of more than `2^MAX_CODE_LEN` leaves, to the
+    /// smallest length that still fits all of them.
+    pub fn mktree(freq: Vec<Tree<K>>) -> Tree<K> {
+        let needed = usize::BITS - freq.len().saturating_sub(1).leading_zeros();
+        Tree::mktree_limited(freq, MAX_CODE_LEN.max(needed as u8))
+    }
+
+    /// Builds an optimal tree from the leaves in `freq` whose codes are at most
+    /// `max_len` bits long.
+    ///
+    /// If the huffman tree exceeds `max_len` the code lengths are recomputed using
+    /// the package-merge algorithm, which yields optimal length limited codes.
+    ///
+    /// Alphabets with less than two leaves are padded with zero weight leaves for the
+    /// smallest keys not contained in `freq`. This way every tree describes a complete
+    /// code: an empty input is stored as two 1 bit codes that are never used, and the
+    /// only byte of a single byte alphabet is encoded as a 1 bit code.
+    ///
+    /// The resulting code lengths only depend on the weights of the leaves, not on
+    /// their order in `freq`. Ties are broken deterministically:
+    /// - the lightest two trees are merged first, the lighter one becoming the left child
+    /// - leaves of equal weight are taken in ascending order of their key
+    /// - leaves are taken before merged nodes of equal weight, merged nodes of equal
+    ///   weight in the order they were created
+    ///
+    /// The package-merge algorithm follows the same order for leaves of equal weight.
+    /// Together with canonical codes this makes the encoded output a pure function
+    /// of the key histogram.
+    ///
+    /// # Panics
+    /// This method panics if `max_len` is too small to assign a code to every leaf
+    pub fn mktree_limited(mut freq: Vec<Tree<K>>, max_len: u8) -> Tree<K> {
+        freq.sort_by_key(|tree| match tree {
+            Tree::Leaf(key, _) => key.index(),
+            _ => usize::MAX,
+        });
+        let mut unused = (0..=K::MAX_INDEX).map(K::from_index);
+        while freq.len() < 2 {
+            let key = unused
+                .find(|key| {
+                    !freq
+                        .iter()
+                        .any(|leaf| matches!(leaf, Tree::Leaf(other, _) if other == key))
+                })
+                .expect("at most one key is used");
+            freq.push(Tree::Leaf(key, 0));
+        }
+        assert!(
+            freq.len() <= 1 << max_len.min(usize::BITS as u8 - 1),
+            "{} leaves do not fit into codes of {max_len} bits",
+            freq.len()
+        );
+        let tree = Tree::huffman(freq);
+        if tree.sparse_lengths().iter().all(|&(_, len)| len <= max_len) {
+            return tree;
+        }
+
+        let mut leaves = tree.leaves();
+        leaves.sort_unstable_by_key(|&(key, _)| key);
+        let weights: Vec<usize> = leaves.iter().map(|&(_, weight)| weight).collect();
+        let lengths: Vec<(K, u8)> = leaves
+            .iter()
+            .zip(package_merge(&weights, max_len))
+            .map(|(&(key, _), len)| (key, len))
+            .collect();
+        let mut tree =
+            Tree::from_sparse_lengths(&lengths).expect("package-merge yields complete codes");
+        tree.reweigh(&leaves);
+        tree
+    }
+
+    /// Returns the code lengths of an optimal code for `counts`, indexed by [`Key::index`],
+    /// whose codes are at most `max_len` bits long.
+    ///
+    /// Unlike [`Tree::code_lengths`] the result has exactly one length per count. Keys
+    /// with a count of 0 get no code unless they pad the alphabet to two codes as in
+    /// [`Tree::mktree_limited`].
+    ///
+    /// # Panics
+    /// This method panics if `max_len` is too small to assign a code to every used key
+    /// or if `counts` has less than two entries
+    pub fn limited_lengths(counts: &[usize], max_len: u8) -> Vec<u8> {
+        let freq = counts
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .map(|(key, &count)| Tree::Leaf(K::from_index(key), count))
+            .collect();
+        let mut lengths = Tree::mktree_limited(freq, max_len).code_lengths();
+        assert!(
+            lengths.len() <= counts.len(),
+            "padding keys exceed `counts`"
+        );
+        lengths.resize(counts.len(), 0);
+        lengths
+    }
+
+    /// Returns key and weight of all leaves under `self`
+    fn leaves(&self) -> Vec<(K, usize)> {
+        fn collect<K: Key>(node: &Tree<K>, leaves: &mut Vec<(K, usize)>) {
+            match node {
+                Tree::Root(left, right) | Tree::Node(left, right, _) => {
+                    collect(left, leaves);
+                    collect(right, leaves);
+                }
+                Tree::Leaf(key, weight) => leaves.push((*key, *weight)),
             }
-            255 => Ok(Tree::Root(
-                Box::new(Tree::load(input)?),
-                Box::new(Tree::load(input)?),
-            )),
-            _ => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Tree format broken",
-            )),
         }
+        let mut leaves = Vec::new();
+        collect(self, &mut leaves);
+        leaves
     }
 
-    pub fn mktree(mut freq: Vec<Tree>) -> Tree {
+    /// Sets the weights of all leaves to their weight in `weights`, which is sorted by key,
+    /// and updates the nodes accordingly
+    ///
+    /// # Returns
+    /// The weight of `self`
+    fn reweigh(&mut self, weights: &[(K, usize)]) -> usize {
+        match self {
+            Tree::Leaf(key, weight) => {
+                let index = weights
+                    .binary_search_by_key(key, |&(key, _)| key)
+                    .expect("all leaves have a weight");
+                *weight = weights[index].1;
+                *weight
+            }
+            Tree::Node(left, right, weight) => {
+                *weight = left.reweigh(weights) + right.reweigh(weights);
+                *weight
+            }
+            Tree::Root(left, right) => left.reweigh(weights) + right.reweigh(weights),
+        }
+    }
+
+    /// Builds the huffman tree without any limits on the code length
+    ///
+    /// The two lightest trees are merged until one is left, using a binary heap this
+    /// takes `O(n log n)` for `n` leaves.
+    fn huffman(freq: Vec<Tree<K>>) -> Tree<K> {
+        // trees are moved out of `nodes` once they are merged
+        let mut nodes: Vec<Option<Tree<K>>> = Vec::with_capacity(2 * freq.len());
+        let mut heap = BinaryHeap::with_capacity(freq.len());
+        for tree in freq {
+            heap.push(Reverse((tree.weight(), nodes.len())));
+            nodes.push(Some(tree));
+        }
+
         loop {
-            let mut bigger = (0, usize::MAX);
-            let mut smaller = (0, usize::MAX);
-            for (num, node) in freq.iter().enumerate() {
-                match node {
-                    Tree::Leaf(_, value) | Tree::Node(_, _, value) => {
-                        if value < &bigger.1 {
-                            if value < &smaller.1 {
-                                bigger = smaller;
-                                smaller = (num, *value);
-                            } else {
-                                bigger = (num, *value);
-                            }
-                        }
-                    }
-                    Tree::Root(_, _) => (),
+            let Reverse((smaller, left)) = heap.pop().expect("at least two trees are left");
+            let Reverse((bigger, right)) = heap.pop().expect("at least two trees are left");
+            let left = Box::new(nodes[left].take().expect("trees are merged only once"));
+            let right = Box::new(nodes[right].take().expect("trees are merged only once"));
+            if heap.is_empty() {
+                return Tree::Root(left, right);
+            }
+            heap.push(Reverse((smaller + bigger, nodes.len())));
+            nodes.push(Some(Tree::Node(left, right, smaller + bigger)));
+        }
+    }
+
+    fn weight(&self) -> usize {
+        match self {
+            Tree::Leaf(_, weight) | Tree::Node(_, _, weight) => *weight,
+            Tree::Root(left, right) => left.weight() + right.weight(),
+        }
+    }
+}
+
+/// Computes optimal code lengths of at most `max_len` bits for `weights`
+/// using the package-merge algorithm.
+///
+/// The lengths are returned in the same order as `weights`.
+fn package_merge(weights: &[usize], max_len: u8) -> Vec<u8> {
+    enum Item {
+        Leaf(usize),
+        Package(usize, usize),
+    }
+    // all items created, referenced by their index
+    let mut items: Vec<(usize, Item)> = Vec::new();
+
+    let mut order: Vec<usize> = (0..weights.len()).collect();
+    order.sort_by_key(|&num| weights[num]);
+    let leaves: Vec<usize> = order
+        .into_iter()
+        .map(|num| {
+            items.push((weights[num], Item::Leaf(num)));
+            items.len() - 1
+        })
+        .collect();
+
+    let mut list = leaves.clone();
+    for _ in 1..max_len {
+        let packages: Vec<usize> = list
+            .chunks_exact(2)
+            .map(|pair| {
+                let weight = items[pair[0]].0 + items[pair[1]].0;
+                items.push((weight, Item::Package(pair[0], pair[1])));
+                items.len() - 1
+            })
+            .collect();
+
+        // merge leaves and packages by weight, leaves first on equal weight
+        list = Vec::with_capacity(leaves.len() + packages.len());
+        let (mut leaves, mut packages) = (leaves.iter().peekable(), packages.iter().peekable());
+        loop {
+            let next = match (leaves.peek(), packages.peek()) {
+                (Some(&&leaf), Some(&&package)) if items[package].0 < items[leaf].0 => {
+                    packages.next()
                 }
+                (Some(_), _) => leaves.next(),
+                (None, _) => packages.next(),
+            };
+            match next {
+                Some(&item) => list.push(item),
+                None => break,
             }
-            let left;
-            let right;
-            if smaller.0 > bigger.0 {
-                left = freq.remove(smaller.0);
-                right = freq.remove(bigger.0);
-            } else {
-                right = freq.remove(bigger.0);
-                left = freq.remove(smaller.0);
+        }
+    }
+
+    // every leaf contained in the first 2n - 2 items adds one bit to its code
+    let mut lengths = vec![0u8; weights.len()];
+    let mut stack: Vec<usize> = list
+        .into_iter()
+        .take((2 * weights.len()).saturating_sub(2))
+        .collect();
+    while let Some(item) = stack.pop() {
+        match items[item].1 {
+            Item::Leaf(num) => lengths[num] += 1,
+            Item::Package(left, right) => {
+                stack.push(left);
+                stack.push(right);
             }
-            if freq.is_empty() {
-                return Tree::Root(Box::new(left), Box::new(right));
+        }
+    }
+    lengths
+}
+
+/// Assigns canonical codes to all keys with a non zero length in `lengths`.
+///
+/// Shorter codes come first, codes of equal length are ordered by their key.
+/// The returned codes are sorted in ascending order.
+///
+/// # Errors
+/// This function returns an error if `lengths` do not describe a complete prefix code
+pub fn canonical_codes<K: Key>(lengths: &[u8]) -> Result<Vec<(K, Symbol)>, io::Error> {
+    sparse_canonical_codes(&to_sparse(lengths)?)
+}
+
+/// Assigns canonical codes to all keys with a non zero length in `lengths` like
+/// [`canonical_codes`], `lengths` has to be sorted by key.
+///
+/// # Errors
+/// This function returns an error if `lengths` do not describe a complete prefix code
+/// or are not sorted by key
+pub fn sparse_canonical_codes<K: Key>(lengths: &[(K, u8)]) -> Result<Vec<(K, Symbol)>, io::Error> {
+    if lengths.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+        return Err(invalid_format());
+    }
+    let mut order: Vec<(u8, K)> = lengths
+        .iter()
+        .filter(|&&(_, len)| len > 0)
+        .map(|&(key, len)| (len, key))
+        .collect();
+    order.sort_unstable();
+
+    let mut codes = Vec::with_capacity(order.len());
+    // current code, one bool per bit
+    let mut code: Vec<bool> = Vec::new();
+    for (num, &(len, key)) in order.iter().enumerate() {
+        if num > 0 {
+            // increment the previous code
+            loop {
+                match code.pop() {
+                    Some(true) => continue,
+                    Some(false) => {
+                        code.push(true);
+                        break;
+                    }
+                    // all ones: there is no code left
+                    None => return Err(invalid_code()),
+                }
             }
+        }
+        code.resize(len as usize, false);
 
-            freq.push(Tree::Node(
-                Box::new(left),
-                Box::new(right),
-                smaller.1 + bigger.1,
-            ));
+        let mut sym = Symbol {
+            bytes: Vec::new(),
+            bitpos: 0,
+            bytepos: 0,
+        };
+        for &bit in code.iter() {
+            sym.append_bit(bit);
         }
+        codes.push((key, sym));
     }
+    // a complete code ends with a code consisting of only ones
+    if !code.iter().all(|&bit| bit) || codes.len() < 2 {
+        return Err(invalid_code());
+    }
+    Ok(codes)
 }
 
-impl Display for Tree {
+/// Returns the keys with a non zero length in the dense `lengths`
+fn to_sparse<K: Key>(lengths: &[u8]) -> Result<Vec<(K, u8)>, io::Error> {
+    if lengths.len() > K::MAX_INDEX.saturating_add(1) {
+        return Err(invalid_format());
+    }
+    Ok(lengths
+        .iter()
+        .enumerate()
+        .filter(|(_, &len)| len > 0)
+        .map(|(key, &len)| (K::from_index(key), len))
+        .collect())
+}
+
+fn invalid_code() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "code lengths do not describe a complete prefix code",
+    )
+}
+
+fn invalid_format() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Tree format broken")
+}
+
+impl<K: Key> Display for Tree<K> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        fn show(tree: &Tree, depth: usize, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn show<K: Key>(
+            tree: &Tree<K>,
+            depth: usize,
+            f: &mut std::fmt::Formatter<'_>,
+        ) -> std::fmt::Result {
             match tree {
                 Tree::Leaf(key, val) => {
                     writeln!(f, "{}leaf {} value {}", " ".repeat(depth), key, val)
@@ -170,3 +580,157 @@ impl Display for Tree {
         show(self, 0, f)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(sym: &Symbol) -> String {
+        (0..sym.bits())
+            .map(|i| if sym.bit(i) { '1' } else { '0' })
+            .collect()
+    }
+
+    #[test]
+    fn canonical_rfc1951_example() {
+        // example from RFC 1951 3.2.2 for the alphabet ABCDEFGH
+        let codes = canonical_codes(&[3, 3, 3, 3, 3, 2, 4, 4]).expect("valid code");
+        let codes: Vec<(u8, String)> = codes.iter().map(|(key, sym)| (*key, code(sym))).collect();
+        let expected = [
+            (5, "00"),
+            (0, "010"),
+            (1, "011"),
+            (2, "100"),
+            (3, "101"),
+            (4, "110"),
+            (6, "1110"),
+            (7, "1111"),
+        ];
+        let expected: Vec<(u8, String)> = expected
+            .iter()
+            .map(|(key, code)| (*key, code.to_string()))
+            .collect();
+        assert_eq!(expected, codes);
+    }
+
+    #[test]
+    fn incomplete_code() {
+        assert!(canonical_codes::<u8>(&[1, 2]).is_err());
+        assert!(canonical_codes::<u8>(&[1, 1, 1]).is_err());
+    }
+
+    #[test]
+    fn huffman_lengths() {
+        let weights = [45, 13, 12, 16, 9, 5];
+        let freq = weights
+            .iter()
+            .enumerate()
+            .map(|(key, &weight)| Tree::Leaf(key as u8, weight))
+            .collect();
+        let tree = Tree::mktree(freq);
+        assert_eq!(vec![1, 3, 3, 3, 4, 4], tree.code_lengths());
+        assert_eq!(100, tree.weight());
+    }
+
+    #[test]
+    fn order_independent() {
+        let weights = [3, 1, 4, 1, 5, 9, 2, 6, 5, 3, 5, 8, 9, 7, 9, 3, 2, 3, 8, 4];
+        let leaves = |keys: &mut dyn Iterator<Item = usize>| {
+            keys.map(|key| Tree::Leaf(key as u8, weights[key]))
+                .collect::<Vec<Tree>>()
+        };
+        let forward = Tree::mktree(leaves(&mut (0..weights.len())));
+        let backward = Tree::mktree(leaves(&mut (0..weights.len()).rev()));
+        assert_eq!(forward.code_lengths(), backward.code_lengths());
+
+        let forward = Tree::mktree_limited(leaves(&mut (0..weights.len())), 5);
+        let backward = Tree::mktree_limited(leaves(&mut (0..weights.len()).rev()), 5);
+        assert_eq!(forward.code_lengths(), backward.code_lengths());
+    }
+
+    #[test]
+    fn package_merge_limits() {
+        assert_eq!(vec![2, 2, 2, 2], package_merge(&[1, 1, 2, 4], 2));
+        assert_eq!(vec![3, 3, 2, 1], package_merge(&[1, 1, 2, 4], 3));
+    }
+
+    #[test]
+    fn fibonacci_limited() {
+        let mut fib = (1usize, 1usize);
+        let freq = (0..40u8)
+            .map(|key| {
+                fib = (fib.1, fib.0 + fib.1);
+                Tree::Leaf(key, fib.0)
+            })
+            .collect();
+        let tree = Tree::mktree(freq);
+        let lengths = tree.code_lengths();
+        assert_eq!(40, lengths.len());
+        assert!(lengths.iter().all(|&len| len > 0 && len <= MAX_CODE_LEN));
+        assert!(canonical_codes::<u8>(&lengths).is_ok());
+    }
+
+    #[test]
+    fn limited_lengths() {
+        let mut fib = (1usize, 1usize);
+        let counts: Vec<usize> = (0..30)
+            .map(|key| {
+                fib = (fib.1, fib.0 + fib.1);
+                if key % 3 == 0 {
+                    0
+                } else {
+                    fib.0
+                }
+            })
+            .chain([0, 0])
+            .collect();
+        let lengths = Tree::<u16>::limited_lengths(&counts, 7);
+        assert_eq!(counts.len(), lengths.len());
+        assert!(lengths
+            .iter()
+            .zip(&counts)
+            .all(|(&len, &count)| (len > 0) == (count > 0) && len <= 7));
+        assert_eq!(vec![1, 1, 0], Tree::<u16>::limited_lengths(&[0, 0, 0], 7));
+    }
+
+    #[test]
+    fn store_load() {
+        let freq = (0..20u8)
+            .map(|key| Tree::Leaf(key * 3, key as usize * key as usize + 1))
+            .collect();
+        let tree = Tree::mktree(freq);
+        let mut stored = Vec::new();
+        tree.store(&mut stored).expect("io err");
+
+        let lengths = Tree::<u8>::try_load_lengths(&mut &stored[..]).expect("invalid header");
+        assert_eq!(tree.code_lengths(), lengths);
+        let loaded = Tree::try_load(&mut &stored[..]).expect("invalid header");
+        assert_eq!(tree.make_conversion_map().len(), 20);
+        for (key, sym) in loaded.make_conversion_map() {
+            assert_eq!(code(&tree.make_conversion_map()[&key]), code(&sym));
+        }
+    }
+
+    #[test]
+    fn sparse_lengths() {
+        let max = u32::MAX;
+        let freq = vec![Tree::Leaf(3, 5), Tree::Leaf(max, 1), Tree::Leaf(max - 1, 1)];
+        let tree = Tree::mktree(freq);
+        assert_eq!(vec![(3, 1), (max - 1, 2), (max, 2)], tree.sparse_lengths());
+        let mut stored = Vec::new();
+        tree.store_lengths(&mut stored).expect("io err");
+        // only the lengths of the three keys and the runs of zeros between them
+        assert!(stored.len() <= 16);
+        let lengths = Tree::<u32>::load_sparse_lengths(&mut &stored[..]).expect("invalid lengths");
+        assert_eq!(tree.sparse_lengths(), lengths);
+        assert!(Tree::from_sparse_lengths(&lengths).is_ok());
+
+        // runs past the stored count
+        let mut stored = Vec::new();
+        write_varint(&mut stored, 1 << 32).expect("io err");
+        stored.push(0);
+        write_varint(&mut stored, u64::MAX).expect("io err");
+        assert!(Tree::<u32>::load_sparse_lengths(&mut &stored[..]).is_err());
+        assert!(sparse_canonical_codes(&[(2u8, 1), (1, 1)]).is_err());
+    }
+}
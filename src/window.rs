@@ -1,4 +1,4 @@
-use std::io::BufRead;
+use std::io::{BufRead, Seek, SeekFrom};
 
 pub use crate::error::Error;
 
@@ -7,11 +7,15 @@ pub use crate::error::Error;
 pub struct BitWindow<R> {
     data: R,
     initialized: usize, // number of bits in current that are populated (left to right) -> 0b11100101_xxxxxxxx initialized = 8
-    current: usize,
+    current: u64,
+    /// number of bytes loaded from `data`, see [`BitWindow::bytes_consumed`]
+    loaded: u64,
+    /// reverse the bits of every byte as it is loaded, see [`BitWindow::lsb_first`]
+    reversed: bool,
 }
 
 /// count of bits in the "current" type
-const MAXIBITS: usize = usize::BITS as usize;
+const MAXIBITS: usize = u64::BITS as usize;
 /// how many bits to keep in "current" at any time
 const READAHEAD: usize = 8;
 #[allow(clippy::assertions_on_constants)]
@@ -20,6 +24,12 @@ const _: () = assert!(READAHEAD <= (MAXIBITS - 8), "Readahead must be smaller");
 /// Alias to u8::BITS as usize
 const U8BITS: usize = u8::BITS as usize;
 
+/// bytes loaded by a single refill, one less than fit into "current" so they always fit
+/// behind the [`READAHEAD`] bits
+const REFILL_BYTES: usize = MAXIBITS / U8BITS - 1;
+#[allow(clippy::assertions_on_constants)]
+const _: () = assert!(READAHEAD + REFILL_BYTES * U8BITS <= MAXIBITS);
+
 impl<R> BitWindow<R>
 where
     R: BufRead,
@@ -32,7 +42,7 @@ where
     /// where 0s are actual zeros and 1-5 are the 1 starting indecies for the read bits
     #[inline(always)]
     pub const fn show(&self, amt: usize) -> usize {
-        self.current >> (MAXIBITS - amt)
+        (self.current >> (MAXIBITS - amt)) as usize
     }
 
     /// Tells this buffer that `amt` bits have been consumed from the buffer,
@@ -53,6 +63,7 @@ where
     ///
     ///
     /// [`show`]: BitWindow::show
+    #[inline(always)]
     pub fn consume(&mut self, amt: usize) -> Result<bool, Error> {
         if amt > self.initialized {
             return Err(Error::NoBits);
@@ -77,45 +88,147 @@ where
         self.initialized
     }
 
-    /// loads another byte into `current`
+    /// Returns the number of bytes consumed from the data source, a partially consumed
+    /// byte counts as consumed.
+    ///
+    /// Bytes are counted from the creation of the window, after [`BitWindow::seek_bit`]
+    /// from the start of the data source.
+    pub const fn bytes_consumed(&self) -> u64 {
+        self.loaded - (self.initialized / U8BITS) as u64
+    }
+
+    /// Creates a window over `data` whose bytes are packed starting with the least
+    /// significant bit, as in RFC 1951.
+    ///
+    /// The bits of every byte are reversed as it is loaded, so [`show`] returns the bits
+    /// in the order they were packed. Codes packed most significant bit first (like the
+    /// huffman codes of RFC 1951) can be read as usual, numbers packed least significant
+    /// bit first have to be reversed after reading them.
+    ///
+    /// [`show`]: BitWindow::show
+    pub fn lsb_first(data: R) -> Self {
+        let mut window = BitWindow {
+            data,
+            current: 0,
+            initialized: 0,
+            loaded: 0,
+            reversed: true,
+        };
+        // unlike `from` an empty source leaves the window empty instead of hallucinating
+        // a byte, consuming from it fails
+        let _ = window.load();
+        window
+    }
+
+    /// Returns a mutable reference to the underlying data source.
+    ///
+    /// Reading from it skips the bits that were loaded into the window already.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.data
+    }
+
+    /// Returns the underlying data source.
+    ///
+    /// Bits that were loaded into the window but not consumed yet are lost.
+    pub fn into_inner(self) -> R {
+        self.data
+    }
+
+    /// loads [`REFILL_BYTES`] bytes into `current`, or as many as are left
+    ///
+    /// If the underlying data source has at least 8 bytes buffered they are loaded as a
+    /// single word, otherwise byte by byte in [`BitWindow::load_bytes`], which is kept out
+    /// of line so the inlined refill stays small.
     ///
     /// # Safety
     /// To ensure safe operation this method SHALL NOT be called when `self.initialized > 8`
-    /// called when `self.initialized <= 8`
     ///
     /// # Returns
-    /// This method returns `true` when the underlying data source has reached EOF.
+    /// This method returns `true` when the underlying data source has reached EOF
+    /// before any byte was loaded.
     /// In this case no bits have been loaded into `self.current`
     ///
     /// # Errors
     /// This method returns an I/O error if the underlaying data source produced
     /// one during read.
-    /// In this case no bits have been loaded into `self.current`
     #[inline(always)]
     fn load(&mut self) -> Result<bool, Error> {
         let data = self.data.fill_buf()?;
-        match data.first() {
-            Some(&byte) => {
-                self.append_byte(byte);
-                self.data.consume(1);
-                Ok(false)
+        if let Some(word) = data.first_chunk::<8>() {
+            let mut word = u64::from_be_bytes(*word);
+            if self.reversed {
+                word = word.reverse_bits().swap_bytes();
             }
-            None => Ok(true),
+            // the last byte of the word does not fit behind the initialized bits
+            self.current |= (word & !0xFF) >> self.initialized;
+            self.initialized += REFILL_BYTES * U8BITS;
+            self.loaded += REFILL_BYTES as u64;
+            self.data.consume(REFILL_BYTES);
+            return Ok(false);
         }
+        self.load_bytes()
+    }
+
+    /// loads up to [`REFILL_BYTES`] bytes one at a time, near the end of the data source
+    /// or of its buffer
+    #[cold]
+    #[inline(never)]
+    fn load_bytes(&mut self) -> Result<bool, Error> {
+        let mut loaded = 0;
+        while loaded < REFILL_BYTES {
+            let data = self.data.fill_buf()?;
+            if data.is_empty() {
+                break;
+            }
+            let byte = data[0];
+            self.data.consume(1);
+            self.append_byte(byte);
+            loaded += 1;
+        }
+        Ok(loaded == 0)
     }
 
     /// Appends `byte` to `self.current`
     ///
     /// Appending is done with bit accuracy, meaning the user has to make sure
-    /// that `self.initialized <= 8`.
+    /// that `self.initialized <= MAXIBITS - 8`.
     ///
     /// # Safety
-    /// This function produces undefined behavior when called while `self.initialized > 8`
+    /// This function produces undefined behavior when called while
+    /// `self.initialized > MAXIBITS - 8`
     #[inline(always)]
-    fn append_byte(&mut self, byte: u8) {
+    fn append_byte(&mut self, mut byte: u8) {
+        if self.reversed {
+            byte = byte.reverse_bits();
+        }
         let shift = (MAXIBITS - U8BITS) - self.initialized;
-        self.current |= (byte as usize) << shift;
+        self.current |= (byte as u64) << shift;
         self.initialized += U8BITS;
+        self.loaded += 1;
+    }
+}
+
+impl<R> BitWindow<R>
+where
+    R: BufRead + Seek,
+{
+    /// Positions the window at bit `offset` of the underlying data source, counted from
+    /// its start.
+    ///
+    /// The window is refilled from the byte containing the bit, the bits before it in
+    /// that byte are skipped.
+    ///
+    /// # Errors
+    /// This method returns an error if seeking or reading the underlying data source
+    /// failed, or if `offset` is past its end
+    pub fn seek_bit(&mut self, offset: u64) -> Result<(), Error> {
+        self.data.seek(SeekFrom::Start(offset / 8))?;
+        self.current = 0;
+        self.initialized = 0;
+        self.loaded = offset / 8;
+        self.load()?;
+        self.consume((offset % 8) as usize)?;
+        Ok(())
     }
 }
 
@@ -124,16 +237,18 @@ where
     R: BufRead,
 {
     fn from(mut value: R) -> Self {
-        let &initial = value
-            .fill_buf()
-            .map_err(|_| ())
-            .and_then(|buf| buf.first().map_or(Err(()), Ok))
-            .unwrap_or(&0);
-        value.consume(1);
+        let initial = value.fill_buf().ok().and_then(|buf| buf.first().copied());
+        if initial.is_some() {
+            value.consume(1);
+        }
+        let initial = initial.unwrap_or(0);
         BitWindow {
             data: value,
-            current: (initial as usize) << (MAXIBITS - U8BITS),
+            current: (initial as u64) << (MAXIBITS - U8BITS),
             initialized: U8BITS,
+            // a hallucinated byte counts as loaded as well
+            loaded: 1,
+            reversed: false,
         }
     }
 }
@@ -224,6 +339,56 @@ mod tests {
         assert!(reader.initialized() >= 8);
     }
 
+    #[test]
+    fn lsb_first() {
+        let data = [0b10011010, 0b00000001];
+        let mut reader = BitWindow::lsb_first(&data[..]);
+
+        assert_eq!(reader.show(8), 0b01011001);
+        reader.consume(5).expect("io err");
+        assert_eq!(reader.show(4), 0b0011);
+        reader.consume(11).expect("io err");
+        assert_eq!(reader.initialized(), 0);
+        assert!(BitWindow::lsb_first(&[][..]).consume(1).is_err());
+    }
+
+    #[test]
+    fn word_refill() {
+        let data: Vec<u8> = (0..40u8).map(|i| i.wrapping_mul(37) ^ 0x5a).collect();
+        for reversed in [false, true] {
+            let window = |data| match reversed {
+                false => BitWindow::from(data),
+                true => BitWindow::lsb_first(data),
+            };
+            // a buffer of one byte forces loading byte by byte
+            let mut words = window(BufReader::new(&data[..]));
+            let mut bytes = window(BufReader::with_capacity(1, &data[..]));
+            for amt in (1..=8).cycle().take(70) {
+                assert_eq!(words.initialized(), bytes.initialized());
+                assert_eq!(words.show(amt), bytes.show(amt));
+                words.consume(amt).expect("io err");
+                bytes.consume(amt).expect("io err");
+            }
+        }
+    }
+
+    #[test]
+    fn seek_bit() {
+        let data = [0b10011010, 0b11110000, 0b00001111];
+        let mut reader: BitWindow<_> = std::io::Cursor::new(&data).into();
+        reader.consume(6).expect("io err");
+        reader.consume(6).expect("io err");
+
+        reader.seek_bit(3).expect("io err");
+        assert_eq!(reader.show(8), 0b11010111);
+        assert_eq!(reader.bytes_consumed(), 1);
+        reader.seek_bit(20).expect("io err");
+        assert_eq!(reader.initialized(), 4);
+        assert_eq!(reader.bytes_consumed(), 3);
+        assert_eq!(reader.show(4), 0b1111);
+        assert!(reader.seek_bit(25).is_err());
+    }
+
     #[test]
     fn consume_return_eof() {
         let data = [0b10011010; 2];